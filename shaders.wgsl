@@ -2,36 +2,69 @@
 struct VertexOutput {
     [[builtin(position)]] position: vec4<f32>;
     [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] color: vec4<f32>;
+    [[location(2)]] bg_color: vec4<f32>;
 };
 
-struct VertexInput {
-    [[location(0)]] position: vec2<f32>;
-    [[location(1)]] tex_coords: vec2<f32>;
+struct GlyphInput {
+    // Left, top, right and bottom edge
+    [[location(0)]] rect: vec4<f32>;
+    [[location(1)]] tex_rect: vec4<f32>;
+    [[location(2)]] color: u32;
+    [[location(3)]] bg_color: u32;
+    [[location(4)]] skew: f32;
 };
 struct UniformData {
+    projection: mat4x4<f32>;
     translate: vec2<f32>;
+    srgb_target: u32;
 };
 
-fn preprocess_position(in: vec2<f32>) -> vec2<f32> {
-    return (in - 0.5) * 2.0;
+[[group(0), binding(2)]]
+var<uniform> uniform_data: UniformData;
+
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+    let low = c / 12.92;
+    let high = pow((c + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, c <= vec3<f32>(0.04045));
 }
 
+// Colours are given sRGB encoded. A target that encodes to sRGB itself expects linear values.
+fn target_rgba(c: vec4<f32>) -> vec4<f32> {
+    if (uniform_data.srgb_target != 0u) {
+        return vec4<f32>(srgb_to_linear(c.rgb), c.a);
+    }
+    return c;
+}
 
-[[group(0), binding(2)]]
-var<uniform> uniform_data: UniformData;
+fn target_color(packed: u32) -> vec4<f32> {
+    return target_rgba(unpack4x8unorm(packed));
+}
+
+// Maps a y-up position in pixels to clip space.
+fn project(position: vec2<f32>) -> vec4<f32> {
+    return uniform_data.projection * vec4<f32>(position + uniform_data.translate, 0.0, 1.0);
+}
 
 [[stage(vertex)]]
 fn font_vs_main(
-    input: VertexInput
+    [[builtin(vertex_index)]] vertex_index: u32,
+    input: GlyphInput
 ) -> VertexOutput {
     var out: VertexOutput;
-    var inp_pos = preprocess_position(input.position);
-    inp_pos = inp_pos * 1.0;
-    inp_pos = inp_pos + uniform_data.translate / 4.0;
-    out.position =  vec4<f32>(inp_pos, 0.0, 1.0);
-    out.tex_coords = input.tex_coords;
-
-    // out.tex_coords.x = out.tex_coords.x / 10.0;
+    // Two triangles per instance; bit 0 of the corner picks the right edge, bit 1 the bottom.
+    // The corners of the six vertices are packed two bits each.
+    let corner = (0x786u >> (vertex_index * 2u)) & 3u;
+    let right = (corner & 1u) != 0u;
+    let bottom = (corner & 2u) != 0u;
+    var position = vec2<f32>(select(input.rect.x, input.rect.z, right), select(input.rect.y, input.rect.w, bottom));
+    if (!bottom) {
+        position.x = position.x + input.skew;
+    }
+    out.position = project(position);
+    out.tex_coords = vec2<f32>(select(input.tex_rect.x, input.tex_rect.z, right), select(input.tex_rect.y, input.tex_rect.w, bottom));
+    out.color = target_color(input.color);
+    out.bg_color = target_color(input.bg_color);
     return out;
 }
 
@@ -45,32 +78,192 @@ var sampl: sampler;
 [[stage(fragment)]]
 fn font_fs_main(in_var: VertexOutput) -> [[location(0)]] vec4<f32> {
     var coords = in_var.tex_coords;
-    let sampled = textureSample(texture, sampl, coords).zyx;
-    let result: vec4<f32> =  vec4<f32>(1.0 - sampled, sampled.x + sampled.y + sampled.z);
+    let coverage = textureSample(texture, sampl, coords).zyx;
+    let fg = in_var.color;
+    let bg = in_var.bg_color;
+
+    // Subpixel coverage blends per channel between the background and glyph colour.
+    let alpha = min(coverage.x + coverage.y + coverage.z, 1.0) * fg.a;
+    let result: vec4<f32> = vec4<f32>(mix(bg.rgb, fg.rgb, coverage), max(alpha, bg.a));
     return result;
 }
 
 
 
 // Rectangle pass
-struct RectVInput {
-    [[location(0)]] position: vec2<f32>;
+struct RectInput {
+    // Left, bottom, right and top edge
+    [[location(0)]] rect: vec4<f32>;
     [[location(1)]] color: u32;
+    [[location(2)]] gradient_color: u32;
+    [[location(3)]] border_color: u32;
+    [[location(4)]] radius: f32;
+    [[location(5)]] border_width: f32;
+    [[location(6)]] softness: f32;
+    [[location(7)]] gradient_dir: vec2<f32>;
 };
 struct RectVOutput {
     [[builtin(position)]] position: vec4<f32>;
-    [[location(0)]] color: vec4<f32>;
+    // Position relative to the centre of the rect
+    [[location(0)]] local: vec2<f32>;
+    [[location(1)]] half_size: vec2<f32>;
+    [[location(2)]] color: vec4<f32>;
+    [[location(3)]] gradient_color: vec4<f32>;
+    [[location(4)]] border_color: vec4<f32>;
+    // Radius, border width and softness
+    [[location(5)]] shape: vec3<f32>;
+    [[location(6)]] gradient_dir: vec2<f32>;
 };
 [[stage(vertex)]]
-fn rect_vs_main(in_var: RectVInput) ->  RectVOutput {
+fn rect_vs_main(
+    [[builtin(vertex_index)]] vertex_index: u32,
+    input: RectInput
+) -> RectVOutput {
     var out: RectVOutput;
-
-    out.position = vec4<f32>(preprocess_position(in_var.position), 0.0, 1.0);
-    out.color = unpack4x8unorm(in_var.color);
+    // Same corner order as the glyphs, with bit 1 picking the top edge.
+    let corner = (0x786u >> (vertex_index * 2u)) & 3u;
+    // Grown by the soft edge so it isn't cut off
+    let low = input.rect.xy - input.softness;
+    let high = input.rect.zw + input.softness;
+    let position = vec2<f32>(select(low.x, high.x, (corner & 1u) != 0u), select(low.y, high.y, (corner & 2u) != 0u));
+    out.position = project(position);
+    out.local = position - (input.rect.xy + input.rect.zw) * 0.5;
+    out.half_size = (input.rect.zw - input.rect.xy) * 0.5;
+    out.color = target_color(input.color);
+    out.gradient_color = target_color(input.gradient_color);
+    out.border_color = target_color(input.border_color);
+    let radius = min(input.radius, min(out.half_size.x, out.half_size.y));
+    out.shape = vec3<f32>(radius, input.border_width, input.softness);
+    out.gradient_dir = input.gradient_dir;
     return out;
 }
 
+// Distance to the edge of a rounded box centred on the origin, negative inside.
+fn rounded_box_sdf(p: vec2<f32>, half_size: vec2<f32>, radius: f32) -> f32 {
+    let q = abs(p) - half_size + radius;
+    return length(max(q, vec2<f32>(0.0))) + min(max(q.x, q.y), 0.0) - radius;
+}
+
+// Fraction of a pixel at `distance` from an edge that is inside, fading over `softness` pixels.
+fn coverage(distance: f32, softness: f32) -> f32 {
+    return clamp(0.5 - distance / softness, 0.0, 1.0);
+}
+
 [[stage(fragment)]]
 fn rect_fs_main(in_var: RectVOutput) -> [[location(0)]] vec4<f32> {
-    return in_var.color.xyzw;
-}
\ No newline at end of file
+    let radius = in_var.shape.x;
+    let border_width = in_var.shape.y;
+    let softness = in_var.shape.z;
+    let distance = rounded_box_sdf(in_var.local, in_var.half_size, radius);
+
+    // 0 at the edge the gradient starts from, 1 at the opposite one
+    let extent = dot(in_var.half_size, abs(in_var.gradient_dir));
+    let t = clamp(dot(in_var.local, in_var.gradient_dir) / max(2.0 * extent, 0.0001) + 0.5, 0.0, 1.0);
+    var color = mix(in_var.color, in_var.gradient_color, t);
+    if (border_width > 0.0) {
+        color = mix(in_var.border_color, color, coverage(distance + border_width, softness));
+    }
+    return vec4<f32>(color.rgb, color.a * coverage(distance, softness));
+}
+
+// Line pass
+struct LineInput {
+    [[location(0)]] a: vec2<f32>;
+    [[location(1)]] b: vec2<f32>;
+    [[location(2)]] width: f32;
+    [[location(3)]] color: u32;
+    [[location(4)]] round_ends: u32;
+};
+struct LineVOutput {
+    [[builtin(position)]] position: vec4<f32>;
+    // Distance along the segment from `a`, and across it from its centre line
+    [[location(0)]] local: vec2<f32>;
+    // Segment length and half the line width
+    [[location(1)]] size: vec2<f32>;
+    [[location(2)]] color: vec4<f32>;
+    [[location(3), interpolate(flat)]] round_ends: u32;
+};
+[[stage(vertex)]]
+fn line_vs_main(
+    [[builtin(vertex_index)]] vertex_index: u32,
+    input: LineInput
+) -> LineVOutput {
+    var out: LineVOutput;
+    let corner = (0x786u >> (vertex_index * 2u)) & 3u;
+    let delta = input.b - input.a;
+    let len = length(delta);
+    var dir = vec2<f32>(1.0, 0.0);
+    if (len > 0.0) {
+        dir = delta / len;
+    }
+    let normal = vec2<f32>(-dir.y, dir.x);
+    // Room for round ends and the antialiased edge
+    let extend = input.width * 0.5 + 1.0;
+    let local = vec2<f32>(select(-extend, len + extend, (corner & 1u) != 0u), select(-extend, extend, (corner & 2u) != 0u));
+    out.position = project(input.a + dir * local.x + normal * local.y);
+    out.local = local;
+    out.size = vec2<f32>(len, input.width * 0.5);
+    out.color = target_color(input.color);
+    out.round_ends = input.round_ends;
+    return out;
+}
+
+[[stage(fragment)]]
+fn line_fs_main(in_var: LineVOutput) -> [[location(0)]] vec4<f32> {
+    let along = in_var.local.x;
+    let across = abs(in_var.local.y);
+    let half_width = in_var.size.y;
+    var distance = across - half_width;
+    if (along < 0.0) {
+        if ((in_var.round_ends & 1u) != 0u) {
+            distance = length(vec2<f32>(along, across)) - half_width;
+        } else {
+            distance = max(distance, -along);
+        }
+    } else if (along > in_var.size.x) {
+        let past = along - in_var.size.x;
+        if ((in_var.round_ends & 2u) != 0u) {
+            distance = length(vec2<f32>(past, across)) - half_width;
+        } else {
+            distance = max(distance, past);
+        }
+    }
+    return vec4<f32>(in_var.color.rgb, in_var.color.a * coverage(distance, 1.0));
+}
+
+// Image pass
+struct ImageInput {
+    // Left, bottom, right and top edge
+    [[location(0)]] rect: vec4<f32>;
+    // Left, top, right and bottom edge in the atlas
+    [[location(1)]] tex_rect: vec4<f32>;
+    [[location(2)]] tint: u32;
+    [[location(3)]] opacity: f32;
+};
+struct ImageVOutput {
+    [[builtin(position)]] position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] tint: vec4<f32>;
+};
+[[stage(vertex)]]
+fn image_vs_main(
+    [[builtin(vertex_index)]] vertex_index: u32,
+    input: ImageInput
+) -> ImageVOutput {
+    var out: ImageVOutput;
+    let corner = (0x786u >> (vertex_index * 2u)) & 3u;
+    let right = (corner & 1u) != 0u;
+    let top = (corner & 2u) != 0u;
+    out.position = project(vec2<f32>(select(input.rect.x, input.rect.z, right), select(input.rect.y, input.rect.w, top)));
+    out.tex_coords = vec2<f32>(select(input.tex_rect.x, input.tex_rect.z, right), select(input.tex_rect.w, input.tex_rect.y, top));
+    out.tint = target_color(input.tint);
+    out.tint.a = out.tint.a * input.opacity;
+    return out;
+}
+
+[[stage(fragment)]]
+fn image_fs_main(in_var: ImageVOutput) -> [[location(0)]] vec4<f32> {
+    // The atlas holds RGBA bytes in a BGRA texture
+    let texel = textureSample(texture, sampl, in_var.tex_coords).zyxw;
+    return target_rgba(texel) * in_var.tint;
+}
@@ -0,0 +1,377 @@
+use crate::text::TextStyle;
+
+/// Receives the actions decoded by [`Parser`].
+pub trait Perform {
+    fn print(&mut self, c: char);
+    /// C0 control characters that are not part of an escape sequence.
+    fn execute(&mut self, _c: char) {}
+    fn csi_dispatch(&mut self, _params: &[u16], _private: Option<char>, _action: char) {}
+    fn esc_dispatch(&mut self, _intermediate: Option<char>, _action: char) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParserState {
+    Ground,
+    Escape,
+    EscapeIntermediate(char),
+    Csi,
+    CsiIgnore,
+    Osc,
+    OscEscape,
+}
+
+/// Escape sequence decoder. State is kept between calls to `advance`, so sequences
+/// split across writes are decoded correctly.
+pub struct Parser {
+    state: ParserState,
+    params: Vec<u16>,
+    param: Option<u16>,
+    private: Option<char>,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self {
+            state: ParserState::Ground,
+            params: Vec::new(),
+            param: None,
+            private: None,
+        }
+    }
+}
+
+impl Parser {
+    const MAX_PARAMS: usize = 32;
+
+    pub fn advance<P: Perform>(&mut self, performer: &mut P, input: &str) {
+        for c in input.chars() {
+            self.advance_char(performer, c);
+        }
+    }
+
+    fn advance_char<P: Perform>(&mut self, performer: &mut P, c: char) {
+        match self.state {
+            ParserState::Ground => match c {
+                '\x1b' => self.state = ParserState::Escape,
+                '\n' | '\r' | '\t' => performer.print(c),
+                c if (c as u32) < 0x20 || c == '\x7f' => performer.execute(c),
+                c => performer.print(c),
+            },
+            ParserState::Escape => match c {
+                '[' => {
+                    self.params.clear();
+                    self.param = None;
+                    self.private = None;
+                    self.state = ParserState::Csi;
+                }
+                ']' => self.state = ParserState::Osc,
+                ' '..='/' => self.state = ParserState::EscapeIntermediate(c),
+                _ => {
+                    performer.esc_dispatch(None, c);
+                    self.state = ParserState::Ground;
+                }
+            },
+            ParserState::EscapeIntermediate(intermediate) => {
+                performer.esc_dispatch(Some(intermediate), c);
+                self.state = ParserState::Ground;
+            }
+            ParserState::Csi => match c {
+                '0'..='9' => {
+                    let digit = c as u16 - '0' as u16;
+                    self.param = Some(self.param.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                }
+                ';' | ':' => self.push_param(),
+                '<'..='?' if self.params.is_empty() && self.param.is_none() => self.private = Some(c),
+                '@'..='~' => {
+                    self.push_param();
+                    performer.csi_dispatch(&self.params, self.private, c);
+                    self.state = ParserState::Ground;
+                }
+                '\x1b' => self.state = ParserState::Escape,
+                _ => self.state = ParserState::CsiIgnore,
+            },
+            ParserState::CsiIgnore => if ('@'..='~').contains(&c) {
+                self.state = ParserState::Ground;
+            },
+            ParserState::Osc => match c {
+                '\x07' => self.state = ParserState::Ground,
+                '\x1b' => self.state = ParserState::OscEscape,
+                _ => {}
+            },
+            ParserState::OscEscape => self.state = ParserState::Ground,
+        }
+    }
+
+    fn push_param(&mut self) {
+        if self.params.len() < Self::MAX_PARAMS {
+            self.params.push(self.param.take().unwrap_or(0));
+        }
+        self.param = None;
+    }
+}
+
+/// Turns the bytes of successive writes into text. A multi-byte character split between two
+/// writes is held back until the rest of it arrives.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    incomplete: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// The text completed by `buf`. Invalid UTF-8 is an error, and `buf` is then discarded.
+    pub fn decode(&mut self, buf: &[u8]) -> std::io::Result<String> {
+        let held = self.incomplete.len();
+        self.incomplete.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.incomplete) {
+            Ok(_) => self.incomplete.len(),
+            // Only the end is cut off, the next write may complete it
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                self.incomplete.truncate(held);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+            }
+        };
+        let rest = self.incomplete.split_off(valid);
+        let text = std::mem::replace(&mut self.incomplete, rest);
+        Ok(String::from_utf8(text).unwrap())
+    }
+}
+
+/// Current SGR ("select graphic rendition") attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Sgr {
+    pub fg: Option<[u8; 4]>,
+    pub bg: Option<[u8; 4]>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Sgr {
+    const ANSI_COLORS: [[u8; 3]; 16] = [
+        [0, 0, 0], [205, 0, 0], [0, 205, 0], [205, 205, 0],
+        [0, 0, 238], [205, 0, 205], [0, 205, 205], [229, 229, 229],
+        [127, 127, 127], [255, 0, 0], [0, 255, 0], [255, 255, 0],
+        [92, 92, 255], [255, 0, 255], [0, 255, 255], [255, 255, 255],
+    ];
+
+    pub fn palette(idx: u8) -> [u8; 4] {
+        let [r, g, b] = match idx {
+            0..=15 => Self::ANSI_COLORS[idx as usize],
+            16..=231 => {
+                let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+                let i = idx - 16;
+                [level(i / 36), level(i / 6 % 6), level(i % 6)]
+            }
+            _ => {
+                let v = 8 + (idx - 232) * 10;
+                [v, v, v]
+            }
+        };
+        [r, g, b, 255]
+    }
+
+    /// Parses the colour following a 38/48 selector, returning it and the number of
+    /// parameters consumed.
+    fn extended_color(params: &[u16]) -> (Option<[u8; 4]>, usize) {
+        let clamp = |v: &u16| (*v).min(255) as u8;
+        match params {
+            [5, idx, ..] => (Some(Self::palette(clamp(idx))), 2),
+            [2, r, g, b, ..] => (Some([clamp(r), clamp(g), clamp(b), 255]), 4),
+            [5, ..] | [2, ..] => (None, params.len()),
+            _ => (None, 1.min(params.len())),
+        }
+    }
+
+    pub fn apply(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            *self = Sgr::default();
+            return;
+        }
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => *self = Sgr::default(),
+                1 => self.bold = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.reverse = true,
+                22 => self.bold = false,
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.reverse = false,
+                p @ 30..=37 => self.fg = Some(Self::palette((p - 30) as u8)),
+                p @ 90..=97 => self.fg = Some(Self::palette((p - 90 + 8) as u8)),
+                p @ 40..=47 => self.bg = Some(Self::palette((p - 40) as u8)),
+                p @ 100..=107 => self.bg = Some(Self::palette((p - 100 + 8) as u8)),
+                39 => self.fg = None,
+                49 => self.bg = None,
+                selector @ (38 | 48) => {
+                    let (color, used) = Self::extended_color(&params[i + 1..]);
+                    if selector == 38 {
+                        self.fg = color.or(self.fg);
+                    } else {
+                        self.bg = color.or(self.bg);
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    pub fn style(&self) -> TextStyle {
+        let mut fg = self.fg.unwrap_or(TextStyle::DEFAULT.color);
+        let mut bg = self.bg.unwrap_or(TextStyle::DEFAULT.background);
+        if self.reverse {
+            let default_bg = [255, 255, 255, 255];
+            std::mem::swap(&mut fg, &mut bg);
+            if fg[3] == 0 {
+                fg = default_bg;
+            }
+        }
+        TextStyle {
+            color: fg,
+            background: bg,
+            bold: self.bold,
+            italic: self.italic,
+            underline: self.underline,
+        }
+    }
+}
+
+/// Collects printable text into runs of equal style, handling only SGR sequences.
+/// Everything else (cursor movement, OSC titles, ...) is dropped.
+#[derive(Default)]
+pub struct StyledRuns {
+    pub sgr: Sgr,
+    pub runs: Vec<(String, TextStyle)>,
+}
+
+impl Perform for StyledRuns {
+    fn print(&mut self, c: char) {
+        let style = self.sgr.style();
+        match self.runs.last_mut() {
+            Some((text, s)) if *s == style => text.push(c),
+            _ => self.runs.push((c.to_string(), style)),
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &[u16], private: Option<char>, action: char) {
+        if action == 'm' && private.is_none() {
+            self.sgr.apply(params);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(chunks: &[&str]) -> Vec<(String, TextStyle)> {
+        let mut parser = Parser::default();
+        let mut runs = StyledRuns::default();
+        for chunk in chunks {
+            parser.advance(&mut runs, chunk);
+        }
+        runs.runs
+    }
+
+    fn run(text: &str, style: TextStyle) -> (String, TextStyle) {
+        (text.to_owned(), style)
+    }
+
+    #[test]
+    fn basic_colors() {
+        let red = Sgr::palette(1);
+        let bright_blue = Sgr::palette(12);
+        assert_eq!(parse(&["\x1b[31ma\x1b[94mb\x1b[39mc"]), vec![
+            run("a", TextStyle::with_color(red)),
+            run("b", TextStyle::with_color(bright_blue)),
+            run("c", TextStyle::DEFAULT),
+        ]);
+        let runs = parse(&["\x1b[42ma\x1b[103mb\x1b[49mc"]);
+        assert_eq!(runs[0].1.background, Sgr::palette(2));
+        assert_eq!(runs[1].1.background, Sgr::palette(11));
+        assert_eq!(runs[2].1, TextStyle::DEFAULT);
+    }
+
+    #[test]
+    fn palette_colors() {
+        assert_eq!(Sgr::palette(16), [0, 0, 0, 255]);
+        assert_eq!(Sgr::palette(196), [255, 0, 0, 255]);
+        assert_eq!(Sgr::palette(232), [8, 8, 8, 255]);
+        assert_eq!(Sgr::palette(255), [238, 238, 238, 255]);
+        let runs = parse(&["\x1b[38;5;196ma\x1b[48;5;232mb"]);
+        assert_eq!(runs[0].1, TextStyle::with_color([255, 0, 0, 255]));
+        assert_eq!(runs[1].1.background, [8, 8, 8, 255]);
+    }
+
+    #[test]
+    fn truecolor() {
+        let runs = parse(&["\x1b[38;2;10;20;30;48;2;40;50;60ma"]);
+        assert_eq!(runs[0].1.color, [10, 20, 30, 255]);
+        assert_eq!(runs[0].1.background, [40, 50, 60, 255]);
+        // Colon separated and out of range components
+        let runs = parse(&["\x1b[38:2:300:0:0ma"]);
+        assert_eq!(runs[0].1.color, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn attributes_and_reset() {
+        let runs = parse(&["\x1b[1;3;4ma\x1b[22;23;24mb\x1b[1mc\x1b[0md\x1b[4me\x1b[mf"]);
+        let styles: Vec<_> = runs.iter().map(|(_, s)| (s.bold, s.italic, s.underline)).collect();
+        assert_eq!(styles, [
+            (true, true, true),
+            (false, false, false),
+            (true, false, false),
+            (false, false, false),
+            (false, false, true),
+            (false, false, false),
+        ]);
+    }
+
+    #[test]
+    fn reverse_video() {
+        let runs = parse(&["\x1b[7ma\x1b[31mb\x1b[27mc"]);
+        assert_eq!(runs[0].1.color, [255, 255, 255, 255]);
+        assert_eq!(runs[0].1.background, TextStyle::DEFAULT.color);
+        assert_eq!(runs[1].1.background, Sgr::palette(1));
+        assert_eq!(runs[2].1, TextStyle::with_color(Sgr::palette(1)));
+    }
+
+    #[test]
+    fn strips_escapes() {
+        let text: String = parse(&["a\x1b[2J\x1b[10;5Hb\x1b]0;title\x07c\x1b]2;t\x1b\\d\x1b(Be\x07f"])
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect();
+        assert_eq!(text, "abcdef");
+    }
+
+    #[test]
+    fn split_sequences() {
+        let whole = parse(&["x\x1b[38;2;1;2;3my"]);
+        for at in 1.."x\x1b[38;2;1;2;3my".len() {
+            let (a, b) = "x\x1b[38;2;1;2;3my".split_at(at);
+            assert_eq!(parse(&[a, b]), whole, "split at {}", at);
+        }
+        let text: String = parse(&["a\x1b]0;ti", "tle\x07b"]).into_iter().map(|(text, _)| text).collect();
+        assert_eq!(text, "ab");
+    }
+
+    #[test]
+    fn split_utf8() {
+        let mut decoder = Utf8Decoder::default();
+        let bytes = "a\u{e9}\u{2500}b".as_bytes();
+        let text: String = bytes.chunks(1).map(|b| decoder.decode(b).unwrap()).collect();
+        assert_eq!(text, "a\u{e9}\u{2500}b");
+
+        assert_eq!(decoder.decode(&bytes[..3]).unwrap(), "a\u{e9}");
+        assert!(decoder.decode(b"\xff").is_err());
+        // The partial character before the invalid write is still completed
+        assert_eq!(decoder.decode(&bytes[3..]).unwrap(), "\u{2500}b");
+    }
+}
@@ -1,21 +1,78 @@
-use wgpu::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, BlendState, Buffer, BufferAddress, BufferBindingType, BufferDescriptor, BufferUsages, ColorTargetState, ColorWrites, Extent3d, FragmentState, FrontFace, PipelineLayoutDescriptor, PrimitiveState, PrimitiveTopology, RenderPipeline, RenderPipelineDescriptor, Sampler, SamplerBindingType, ShaderStages, Texture, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor, VertexBufferLayout, VertexState};
+use std::sync::Arc;
 
-use crate::gpu_device::{device, shader};
+use wgpu::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, BlendState, Buffer, BufferAddress, BufferBinding, BufferBindingType, BufferSize, BufferDescriptor, BufferUsages, ColorTargetState, ColorWrites, Device, Extent3d, FragmentState, FrontFace, MultisampleState, PipelineLayout, PipelineLayoutDescriptor, PrimitiveState, PrimitiveTopology, RenderPipeline, RenderPipelineDescriptor, Sampler, SamplerBindingType, ShaderModule, ShaderStages, Texture, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor, VertexBufferLayout, VertexState};
+
+use bytemuck::{Pod, Zeroable};
+use crate::drawrects::Batch;
+use crate::gpu_device::GpuContext;
+
+/// Contents of the uniform buffer bound by every pass: a projection from y-up pixel coordinates
+/// to clip space, and a pixel offset applied before it.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Uniforms {
+    projection: [[f32; 4]; 4],
+    translate: [f32; 2],
+    /// Non-zero when the target encodes to sRGB itself, so colours must be output linear.
+    srgb_target: u32,
+    _padding: u32,
+}
+
+impl Uniforms {
+    pub fn new(viewport: (u32, u32), translate: (f32, f32), srgb_target: bool) -> Self {
+        let (w, h) = (viewport.0 as f32, viewport.1 as f32);
+        Self {
+            projection: [
+                [2.0 / w, 0.0, 0.0, 0.0],
+                [0.0, 2.0 / h, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [-1.0, -1.0, 0.0, 1.0],
+            ],
+            translate: [translate.0, translate.1],
+            srgb_target: srgb_target as u32,
+            _padding: 0,
+        }
+    }
+}
+
+impl Uniforms {
+    /// The same uniforms with `by` added to the translation.
+    pub fn translated(mut self, by: (f32, f32)) -> Self {
+        self.translate[0] += by.0;
+        self.translate[1] += by.1;
+        self
+    }
+}
+
+unsafe impl Pod for Uniforms {}
+
+unsafe impl Zeroable for Uniforms {}
 
 pub struct BasicRenderState {
+    pub(crate) gpu: Arc<GpuContext>,
     pub(crate) render_pipeline: RenderPipeline,
     pub(crate) bind_group: BindGroup,
     pub(crate) texture: Texture,
 
-    #[allow(unused)]
     sampler: Sampler,
+    /// One `Uniforms` per batch, `UNIFORM_STRIDE` bytes apart, selected with a dynamic offset.
     pub(crate) uniform_buffer: Buffer,
+    uniform_slots: usize,
+    bind_group_layout: BindGroupLayout,
+    layout: PipelineLayout,
+    shader_prefix: &'static str,
+    vert_layout: VertexBufferLayout<'static>,
+    blend: BlendState,
+    target_format: TextureFormat,
 }
 
 impl BasicRenderState {
-    pub(crate) fn new(shader_prefix: &'static str, uniform_size: usize, texture_size: Extent3d, vert_layout: VertexBufferLayout, blend: BlendState) -> Self {
-        let device = device();
-        let shader = shader();
+    /// Distance between the uniforms of consecutive batches; the largest offset alignment a
+    /// device may require of dynamic uniform offsets.
+    pub(crate) const UNIFORM_STRIDE: BufferAddress = 256;
+
+    pub(crate) fn new(gpu: &Arc<GpuContext>, shader_prefix: &'static str, texture_size: Extent3d, vert_layout: VertexBufferLayout<'static>, blend: BlendState, target_format: TextureFormat) -> Self {
+        let device = &gpu.device;
         let bindgrouplayout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: None,
             entries: &[BindGroupLayoutEntry {
@@ -39,55 +96,98 @@ impl BasicRenderState {
                 visibility: ShaderStages::VERTEX_FRAGMENT,
                 ty: BindingType::Buffer {
                     ty: BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
+                    has_dynamic_offset: true,
+                    min_binding_size: BufferSize::new(std::mem::size_of::<Uniforms>() as u64),
                 },
                 count: None,
             }],
         });
-        let texture = device.create_texture(&TextureDescriptor {
+        let texture = Self::create_texture(device, texture_size);
+
+        let uniform_buffer = Self::create_uniform_buffer(device, 1);
+
+        let sampler = crate::create_sampler(device);
+        let bind_group = Self::create_bind_group(device, &bindgrouplayout, &texture, &sampler, &uniform_buffer);
+        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Pipeline Layout"),
+            bind_group_layouts: &[&bindgrouplayout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = Self::create_pipeline(device, &layout, &gpu.shader(), shader_prefix, vert_layout.clone(), blend, target_format, 1);
+        Self {
+            gpu: gpu.clone(),
+            render_pipeline,
+            bind_group,
+            texture,
+            sampler,
+            uniform_buffer,
+            uniform_slots: 1,
+            bind_group_layout: bindgrouplayout,
+            layout,
+            shader_prefix,
+            vert_layout,
+            blend,
+            target_format,
+        }
+    }
+
+    fn create_texture(device: &Device, size: Extent3d) -> Texture {
+        device.create_texture(&TextureDescriptor {
             label: None,
-            size: texture_size,
+            size,
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
+            // Glyph coverage is linear, so the texture never decodes sRGB
             format: TextureFormat::Bgra8Unorm,
             usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-        });
-
-        let uniform_buffer = device.create_buffer(
-            &BufferDescriptor {
-                label: None,
-                size: uniform_size as BufferAddress,
-                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-                mapped_at_creation: false,
-            }
-        );
+        })
+    }
 
+    fn create_bind_group(device: &Device, layout: &BindGroupLayout, texture: &Texture, sampler: &Sampler, uniform_buffer: &Buffer) -> BindGroup {
         let view = texture.create_view(&TextureViewDescriptor::default());
-        let sampler = crate::create_sampler(device);
-        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+        device.create_bind_group(&BindGroupDescriptor {
             label: None,
-            layout: &bindgrouplayout,
+            layout,
             entries: &[BindGroupEntry {
                 binding: 0,
                 resource: BindingResource::TextureView(&view),
             }, BindGroupEntry {
                 binding: 1,
-                resource: BindingResource::Sampler(&sampler),
+                resource: BindingResource::Sampler(sampler),
             }, BindGroupEntry {
                 binding: 2,
-                resource: uniform_buffer.as_entire_binding(),
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: uniform_buffer,
+                    offset: 0,
+                    size: BufferSize::new(std::mem::size_of::<Uniforms>() as u64),
+                }),
             }],
-        });
-        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
-            label: Some("Pipeline Layout"),
-            bind_group_layouts: &[&bindgrouplayout],
-            push_constant_ranges: &[],
-        });
-        let render_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+        })
+    }
+
+    fn create_uniform_buffer(device: &Device, slots: usize) -> Buffer {
+        device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: slots as BufferAddress * Self::UNIFORM_STRIDE,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Replaces the texture with an empty one of `size`. Lets a pass start with a placeholder
+    /// and allocate its real texture once something is drawn from it.
+    pub(crate) fn replace_texture(&mut self, size: Extent3d) {
+        let device = &self.gpu.device;
+        self.texture = Self::create_texture(device, size);
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &self.texture, &self.sampler, &self.uniform_buffer);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_pipeline(device: &Device, layout: &PipelineLayout, shader: &ShaderModule, shader_prefix: &str, vert_layout: VertexBufferLayout, blend: BlendState, target_format: TextureFormat, sample_count: u32) -> RenderPipeline {
+        device.create_render_pipeline(&RenderPipelineDescriptor {
             label: None,
-            layout: Some(&layout),
+            layout: Some(layout),
             vertex: VertexState {
                 module: shader,
                 entry_point: &*format!("{}_{}", shader_prefix, "vs_main"),
@@ -97,7 +197,7 @@ impl BasicRenderState {
                 module: shader,
                 entry_point: &*format!("{}_{}", shader_prefix, "fs_main"),
                 targets: &[ColorTargetState {
-                    format: TextureFormat::Bgra8Unorm,
+                    format: target_format,
                     blend: Some(blend),
                     write_mask: ColorWrites::ALL,
                 }],
@@ -112,15 +212,35 @@ impl BasicRenderState {
                 conservative: false,
             },
             depth_stencil: None,
-            multisample: Default::default(),
+            multisample: MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
             multiview: None,
-        });
-        Self {
-            render_pipeline,
-            bind_group,
-            texture,
-            sampler,
-            uniform_buffer,
+        })
+    }
+
+    /// A pipeline like the current one, but using the entry points of `shader` and rendering to
+    /// a target with `sample_count` samples. Used to swap in reloaded shaders or a new sample
+    /// count once every pipeline built without errors.
+    pub(crate) fn pipeline_with(&self, shader: &ShaderModule, sample_count: u32) -> RenderPipeline {
+        Self::create_pipeline(&self.gpu.device, &self.layout, shader, self.shader_prefix, self.vert_layout.clone(), self.blend, self.target_format, sample_count)
+    }
+
+    /// Writes `uniforms` translated by each batch's offset, growing the buffer if there are
+    /// more batches than slots.
+    pub(crate) fn write_uniforms(&mut self, uniforms: &Uniforms, batches: &[Batch]) {
+        if batches.len() > self.uniform_slots {
+            let device = &self.gpu.device;
+            self.uniform_slots = batches.len() * 2;
+            self.uniform_buffer = Self::create_uniform_buffer(device, self.uniform_slots);
+            self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &self.texture, &self.sampler, &self.uniform_buffer);
+        }
+        let mut data = vec![0; batches.len() * Self::UNIFORM_STRIDE as usize];
+        for (slot, batch) in data.chunks_mut(Self::UNIFORM_STRIDE as usize).zip(batches) {
+            let translated = uniforms.translated(batch.translate);
+            slot[..std::mem::size_of::<Uniforms>()].copy_from_slice(bytemuck::bytes_of(&translated));
         }
+        self.gpu.queue.write_buffer(&self.uniform_buffer, 0, &data);
     }
 }
@@ -1,143 +1,249 @@
-use std::ptr;
+use std::ops::Range;
+use std::sync::Arc;
 use bytemuck::{Pod, Zeroable};
 use lazy_static::lazy_static;
-use wgpu::{Buffer, BufferAddress, BufferDescriptor, BufferSlice, BufferUsages, VertexAttribute, VertexBufferLayout, VertexStepMode};
+use wgpu::{BindGroup, Buffer, BufferAddress, BufferDescriptor, BufferUsages, RenderPass, VertexAttribute, VertexBufferLayout, VertexStepMode};
 
-use crate::gpu_device::device;
+use crate::basic_render_state::BasicRenderState;
+use crate::gpu_device::GpuContext;
 
 lazy_static! {
-    pub static ref TEXT_VERTEX_ATTRIBUTES: [VertexAttribute; 2] =  wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2];
-    pub static ref COLORED_RECT_VERTEX_ATTRIBUTES: [VertexAttribute; 2] =  wgpu::vertex_attr_array![0 => Float32x2, 1 => Uint32];
+    pub static ref GLYPH_INSTANCE_ATTRIBUTES: [VertexAttribute; 5] =  wgpu::vertex_attr_array![0 => Float32x4, 1 => Unorm16x4, 2 => Uint32, 3 => Uint32, 4 => Float32];
+    pub static ref RECT_INSTANCE_ATTRIBUTES: [VertexAttribute; 8] =  wgpu::vertex_attr_array![0 => Float32x4, 1 => Uint32, 2 => Uint32, 3 => Uint32, 4 => Float32, 5 => Float32, 6 => Float32, 7 => Float32x2];
 }
 
-pub type Rect<T> = [T; 4];
-pub type FontDrawRects = DrawRects<FontTriangleVertex>;
-pub type ColoredDrawRects = DrawRects<ColoredTriangleVertex>;
+pub type GlyphInstances = Instances<GlyphInstance>;
+pub type RectInstances = Instances<RectInstance>;
+
+/// Region outside of which an object is not drawn, in the same y-up pixel coordinates as the
+/// objects themselves. `(x, y)` is the bottom left corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClipRect {
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
 
-pub struct DrawRects<VertexT> {
-    pub vertex_buffer: Buffer,
-    pub vertex_buffer_sz: u32,
-    pub cpu_buffer: Vec<VertexT>,
-    pub index_buffer: Vec<u32>,
-    pub layout: VertexBufferLayout<'static>,
-    pub index_buffer_dirty: bool,
+impl ClipRect {
+    pub fn contains(&self, point: (i32, i32)) -> bool {
+        (self.x..self.x + self.w as i32).contains(&point.0) && (self.y..self.y + self.h as i32).contains(&point.1)
+    }
+
+    /// Scissor rectangle in framebuffer pixels (top left origin) for a target of `target` size,
+    /// clamped to the target. `None` if nothing of the target is left to draw on.
+    fn scissor(clip: Option<ClipRect>, target: (u32, u32)) -> Option<(u32, u32, u32, u32)> {
+        let clip = match clip {
+            Some(clip) => clip,
+            None => return Some((0, 0, target.0, target.1)),
+        };
+        let x = |v: i32| (v.max(0) as u32).min(target.0);
+        let y = |v: i32| ((target.1 as i32 - v).max(0) as u32).min(target.1);
+        let (x0, x1) = (x(clip.x), x(clip.x + clip.w as i32));
+        let (y0, y1) = (y(clip.y + clip.h as i32), y(clip.y));
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+        Some((x0, y0, x1 - x0, y1 - y0))
+    }
+}
+
+/// Consecutive quads drawn with the same clip rectangle and translation.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub quads: Range<usize>,
+    pub clip: Option<ClipRect>,
+    /// Pixels the quads are moved by, so objects can move or scroll without rebuilding them.
+    pub translate: (f32, f32),
 }
 
-impl FontDrawRects {
-    pub fn new() -> Self {
-        Self::new_with_layout(&*TEXT_VERTEX_ATTRIBUTES)
+impl Batch {
+    /// Appends `quads`, extending the last batch if it ends there and has the same clip.
+    pub fn push(batches: &mut Vec<Batch>, quads: Range<usize>, clip: Option<ClipRect>) {
+        Self::push_translated(batches, quads, clip, (0.0, 0.0));
+    }
+
+    /// Like `push`, for quads moved by `translate`.
+    pub fn push_translated(batches: &mut Vec<Batch>, quads: Range<usize>, clip: Option<ClipRect>, translate: (f32, f32)) {
+        if quads.is_empty() {
+            return;
+        }
+        match batches.last_mut() {
+            Some(last) if last.quads.end == quads.start && last.clip == clip && last.translate == translate => last.quads.end = quads.end,
+            _ => batches.push(Batch { quads, clip, translate }),
+        }
     }
 }
 
-impl ColoredDrawRects {
-    pub fn new() -> Self {
-        Self::new_with_layout(&*COLORED_RECT_VERTEX_ATTRIBUTES)
+/// Records drawn as one quad per instance, with the corners generated in the vertex shader.
+/// Kept on the CPU and GPU across frames, uploading only changed records.
+pub struct Instances<T> {
+    gpu: Arc<GpuContext>,
+    pub buffer: Buffer,
+    pub buffer_sz: u32,
+    pub cpu_buffer: Vec<T>,
+    pub layout: VertexBufferLayout<'static>,
+    dirty: Option<Range<usize>>,
+}
+
+impl GlyphInstances {
+    pub fn new(gpu: &Arc<GpuContext>) -> Self {
+        Self::new_with_layout(gpu, &*GLYPH_INSTANCE_ATTRIBUTES)
     }
 }
 
-impl<T: bytemuck::Pod> DrawRects<T> {
-    pub fn finish(&mut self) {
-        self.cpu_buffer.clear();
-        self.index_buffer.clear();
+impl RectInstances {
+    pub fn new(gpu: &Arc<GpuContext>) -> Self {
+        Self::new_with_layout(gpu, &*RECT_INSTANCE_ATTRIBUTES)
     }
+}
+
+impl<T: bytemuck::Pod + PartialEq> Instances<T> {
     const START_BUF_SIZE: u32 = 3000;
-    pub fn get_vertex_buf(&self) -> BufferSlice {
-        self.vertex_buffer.slice(..self.cpu_buffer_len() as u64)
+
+    pub fn len(&self) -> usize {
+        self.cpu_buffer.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cpu_buffer.is_empty()
+    }
+    pub fn truncate(&mut self, len: usize) {
+        self.cpu_buffer.truncate(len);
     }
 
-    pub fn extend(&mut self, r: Rect<T>) {
-        fn extend_arr<T, const A: usize>(vec: &mut Vec<T>, arr: [T; A]) {
-            if vec.capacity() < vec.len() + A {
-                vec.reserve(vec.len() + A);
+    /// Overwrites instances starting at `start`, appending past the end. Instances that are
+    /// unchanged are not uploaded again.
+    pub fn set_instances(&mut self, start: usize, instances: &[T]) {
+        assert!(start <= self.len());
+        let overlap = instances.len().min(self.len() - start);
+        for (i, instance) in (start..).zip(&instances[..overlap]) {
+            if self.cpu_buffer[i] != *instance {
+                self.cpu_buffer[i] = *instance;
+                mark_dirty(&mut self.dirty, i..i + 1);
             }
-            unsafe {
-                let end = vec.as_mut_ptr().add(vec.len()) as *mut [T; A];
-                ptr::write(end, arr);
-                vec.set_len(vec.len() + A);
-            }
-
         }
-        let offset = self.cpu_buffer.len() as u32;
-        extend_arr(&mut self.cpu_buffer, r);
-        extend_arr(&mut self.index_buffer, [offset + 2, offset + 1, offset + 0]);
-        extend_arr(&mut self.index_buffer, [offset + 2, offset + 3, offset + 1]);
+        if overlap < instances.len() {
+            let len = self.len();
+            mark_dirty(&mut self.dirty, len..start + instances.len());
+            self.cpu_buffer.extend_from_slice(&instances[overlap..]);
+        }
     }
 
     fn cpu_buffer_len(&self) -> usize {
         self.cpu_buffer.len() * std::mem::size_of::<T>()
     }
-    pub fn get_index_buffer(&self) -> BufferSlice {
-        self.vertex_buffer.slice(self.cpu_buffer_len() as u64..)
-    }
-    pub fn confirm_extends(&mut self, queue: &mut wgpu::Queue) {
-        // Clear all removed elements
-        if self.cpu_buffer_len() + self.index_buffer.len() * 4 > self.vertex_buffer_sz as usize {
-            self.vertex_buffer_sz = (self.cpu_buffer_len() + self.index_buffer.len() * 4) as u32 * 2;
-            self.vertex_buffer = device().create_buffer(&BufferDescriptor {
-                label: None,
-                usage: BufferUsages::VERTEX | BufferUsages::INDEX | BufferUsages::COPY_DST,
-                size: self.vertex_buffer_sz as u64,
-                mapped_at_creation: false,
-            });
+
+    /// Uploads instances changed since the last call, growing the buffer if needed.
+    pub fn confirm_extends(&mut self) {
+        if self.cpu_buffer_len() > self.buffer_sz as usize {
+            self.buffer_sz = self.cpu_buffer_len() as u32 * 2;
+            self.buffer = create_buffer(&self.gpu.device, BufferUsages::VERTEX, self.buffer_sz);
+            self.dirty = Some(0..self.cpu_buffer.len());
+        }
+        if let Some(dirty) = self.dirty.take() {
+            let dirty = dirty.start..dirty.end.min(self.cpu_buffer.len());
+            if !dirty.is_empty() {
+                let offset = dirty.start * std::mem::size_of::<T>();
+                self.gpu.queue.write_buffer(&self.buffer, offset as BufferAddress, bytemuck::cast_slice(&self.cpu_buffer[dirty]));
+            }
         }
-        queue.write_buffer(&self.vertex_buffer, 0_u64, bytemuck::cast_slice(&self.cpu_buffer));
-        queue.write_buffer(&self.vertex_buffer, self.cpu_buffer_len() as BufferAddress, bytemuck::cast_slice(&self.index_buffer));
-        self.index_buffer_dirty = false;
     }
-    pub fn new_with_layout(vertex_attrib_layout: &'static [VertexAttribute]) -> Self {
-        let cpu_buffer = Vec::new();
-        let layout = VertexBufferLayout {
-            array_stride: std::mem::size_of::<T>() as wgpu::BufferAddress,
-            step_mode: VertexStepMode::Vertex,
-            attributes: vertex_attrib_layout,
-        };
 
-        let vertex_buffer = device().create_buffer(&BufferDescriptor {
-            label: None,
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST | BufferUsages::INDEX,
-            size: Self::START_BUF_SIZE as u64,
-            mapped_at_creation: false,
-        });
+    /// Binds the instance buffer and draws each batch with its own scissor rectangle and the
+    /// uniforms `BasicRenderState::write_uniforms` wrote for it. The pipeline must already be set.
+    pub fn draw<'a>(&'a self, p: &mut RenderPass<'a>, bind_group: &'a BindGroup, batches: &[Batch], target: (u32, u32)) {
+        p.set_vertex_buffer(0, self.buffer.slice(..self.cpu_buffer_len() as u64));
+        for (i, batch) in batches.iter().enumerate() {
+            if let Some((x, y, w, h)) = ClipRect::scissor(batch.clip, target) {
+                let offset = i as BufferAddress * BasicRenderState::UNIFORM_STRIDE;
+                p.set_bind_group(0, bind_group, &[offset as u32]);
+                p.set_scissor_rect(x, y, w, h);
+                p.draw(0..6, batch.quads.start as u32..batch.quads.end as u32);
+            }
+        }
+    }
 
+    pub fn new_with_layout(gpu: &Arc<GpuContext>, attributes: &'static [VertexAttribute]) -> Self {
         Self {
-            vertex_buffer,
-            vertex_buffer_sz: Self::START_BUF_SIZE,
-            cpu_buffer,
-            index_buffer: Vec::new(),
-            layout,
-            index_buffer_dirty: true,
+            gpu: gpu.clone(),
+            buffer: create_buffer(&gpu.device, BufferUsages::VERTEX, Self::START_BUF_SIZE),
+            buffer_sz: Self::START_BUF_SIZE,
+            cpu_buffer: Vec::new(),
+            layout: VertexBufferLayout {
+                array_stride: std::mem::size_of::<T>() as wgpu::BufferAddress,
+                step_mode: VertexStepMode::Instance,
+                attributes,
+            },
+            dirty: None,
         }
     }
 }
 
+fn mark_dirty(dirty: &mut Option<Range<usize>>, range: Range<usize>) {
+    *dirty = Some(match dirty.take() {
+        Some(dirty) => dirty.start.min(range.start)..dirty.end.max(range.end),
+        None => range,
+    });
+}
+
+fn create_buffer(device: &wgpu::Device, usage: BufferUsages, size: u32) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        usage: usage | BufferUsages::COPY_DST,
+        size: size as u64,
+        mapped_at_creation: false,
+    })
+}
+
+/// One glyph, background cell or underline drawn by the font pipeline.
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq)]
-pub struct FontTriangleVertex {
-    position: [f32; 2],
-    tex_coords: [f32; 2],
+pub struct GlyphInstance {
+    /// Left, top, right and bottom edge in pixels.
+    pub(crate) rect: [f32; 4],
+    /// Atlas region in the same order, as fractions of the atlas size.
+    tex_rect: [u16; 4],
+    color: [u8; 4],
+    bg_color: [u8; 4],
+    /// Pixels the top edge is shifted right by, for synthetic italics.
+    skew: f32,
 }
 
-impl FontTriangleVertex {
-    pub fn new(pos: (f32, f32), tex: (f32, f32)) -> Self {
+impl GlyphInstance {
+    pub fn new(rect: [f32; 4], tex_rect: [f32; 4], color: [u8; 4], bg_color: [u8; 4], skew: f32) -> Self {
         Self {
-            position: [pos.0, pos.1],
-            tex_coords: [tex.0, tex.1],
+            rect,
+            tex_rect: tex_rect.map(|t| (t.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16),
+            color,
+            bg_color,
+            skew,
         }
     }
 }
 
+/// One rect or shadow drawn by the rect pipeline, shaded from a signed distance field.
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq)]
-pub struct ColoredTriangleVertex {
-    pub(crate) position: [f32; 2],
+pub struct RectInstance {
+    /// Left, bottom, right and top edge in pixels.
+    pub(crate) rect: [f32; 4],
     pub(crate) color: [u8; 4],
+    /// Colour at the far end of the gradient; the same as `color` for a solid fill.
+    pub(crate) gradient_color: [u8; 4],
+    pub(crate) border_color: [u8; 4],
+    pub(crate) radius: f32,
+    pub(crate) border_width: f32,
+    /// Width of the edge falloff in pixels: 1 antialiases the edge, more blurs it.
+    pub(crate) softness: f32,
+    /// Unit vector the gradient runs along.
+    pub(crate) gradient_dir: [f32; 2],
 }
 
 
-unsafe impl Pod for FontTriangleVertex {}
+unsafe impl Pod for GlyphInstance {}
 
-unsafe impl Zeroable for FontTriangleVertex {}
+unsafe impl Zeroable for GlyphInstance {}
 
-unsafe impl Pod for ColoredTriangleVertex {}
+unsafe impl Pod for RectInstance {}
 
-unsafe impl Zeroable for ColoredTriangleVertex {}
+unsafe impl Zeroable for RectInstance {}
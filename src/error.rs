@@ -0,0 +1,72 @@
+use std::fmt::{Display, Formatter};
+
+/// Errors that stop the renderer or terminal from being created, or a frame from being read back.
+#[derive(Debug)]
+pub enum Error {
+    /// No GPU adapter was found, or none that can present to the window.
+    NoAdapter,
+    /// The adapter can't present to the window.
+    UnsupportedSurface,
+    RequestDevice(wgpu::RequestDeviceError),
+    Window(winit::error::OsError),
+    FontNotFound(String),
+    Font(freetype::Error),
+    /// A rendered frame could not be read back from the GPU.
+    BufferMap(wgpu::BufferAsyncError),
+    /// A file needed at startup, such as the shader source, could not be read.
+    Io(String, std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoAdapter => f.write_str("no compatible GPU adapter found"),
+            Error::UnsupportedSurface => f.write_str("window surface not supported by the GPU adapter"),
+            Error::RequestDevice(e) => write!(f, "failed to open GPU device: {}", e),
+            Error::Window(e) => write!(f, "failed to create window: {}", e),
+            Error::FontNotFound(path) => write!(f, "font not found: {}", path),
+            Error::Font(e) => write!(f, "failed to load font: {}", e),
+            Error::BufferMap(e) => write!(f, "failed to read back frame: {}", e),
+            Error::Io(path, e) => write!(f, "failed to read {}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::RequestDevice(e) => Some(e),
+            Error::Window(e) => Some(e),
+            Error::Font(e) => Some(e),
+            Error::BufferMap(e) => Some(e),
+            Error::Io(_, e) => Some(e),
+            Error::NoAdapter | Error::UnsupportedSurface | Error::FontNotFound(_) => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for Error {
+    fn from(e: wgpu::RequestDeviceError) -> Self {
+        Error::RequestDevice(e)
+    }
+}
+
+impl From<wgpu::BufferAsyncError> for Error {
+    fn from(e: wgpu::BufferAsyncError) -> Self {
+        Error::BufferMap(e)
+    }
+}
+
+impl From<winit::error::OsError> for Error {
+    fn from(e: winit::error::OsError) -> Self {
+        Error::Window(e)
+    }
+}
+
+impl From<freetype::Error> for Error {
+    fn from(e: freetype::Error) -> Self {
+        Error::Font(e)
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use winit::event_loop::EventLoopProxy;
+
+/// Decides when to start drawing a frame. Instead of drawing as soon as something changed, it
+/// waits until just enough time is left before the next vblank to update, render and present,
+/// so input arriving in the meantime still makes it into the frame.
+pub struct FrameScheduler {
+    pub enabled: bool,
+    /// Estimated refresh interval, measured from the time between back-to-back presents.
+    frame_interval: Duration,
+    /// With a blocking present mode this is close to the last vblank.
+    last_present: Option<Instant>,
+    /// Moving average of how long a frame takes from the start of the update to the present.
+    frame_time: Duration,
+}
+
+impl Default for FrameScheduler {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            frame_interval: Duration::from_micros(16_667),
+            last_present: None,
+            frame_time: Duration::from_millis(4),
+        }
+    }
+}
+
+impl FrameScheduler {
+    /// Safety margin added to the expected frame time.
+    const MARGIN: Duration = Duration::from_millis(2);
+    /// How long after the last present its vblank phase is still used.
+    const STALE: Duration = Duration::from_secs(1);
+
+    /// When to start drawing a frame that is wanted at `now`.
+    pub fn next_start(&self, now: Instant) -> Instant {
+        let last = match self.last_present {
+            // After idling the vblank phase can't be trusted, so draw straight away
+            Some(last) if self.enabled && now - last < Self::STALE => last,
+            _ => return now,
+        };
+        let budget = self.frame_time + Self::MARGIN;
+        let interval = self.frame_interval.as_nanos();
+        let ahead = (now + budget - last).as_nanos();
+        let vblanks = ahead.div_ceil(interval);
+        last + self.frame_interval * vblanks.max(1) as u32 - budget
+    }
+
+    pub fn frame_presented(&mut self, started: Instant, presented: Instant) {
+        let ewma = |avg: Duration, sample: Duration| (avg * 7 + sample) / 8;
+        self.frame_time = ewma(self.frame_time, presented - started);
+        if let Some(last) = self.last_present {
+            // Only frames presented on consecutive vblanks tell us the refresh interval
+            let interval = presented - last;
+            if interval > self.frame_interval / 2 && interval < self.frame_interval * 3 / 2 {
+                self.frame_interval = ewma(self.frame_interval, interval);
+            }
+        }
+        self.last_present = Some(presented);
+    }
+}
+
+/// Time from a key press to the present of the first frame drawn after it.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyStats {
+    pub last: Option<Duration>,
+    /// Moving average over recent key presses.
+    pub average: Duration,
+    pub max: Duration,
+    pub samples: u64,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: Duration) {
+        self.average = if self.samples == 0 { latency } else { (self.average * 7 + latency) / 8 };
+        self.max = self.max.max(latency);
+        self.last = Some(latency);
+        self.samples += 1;
+    }
+}
+
+/// Requests frames from outside `Terminal`, e.g. by a pass that animates. Clones share the
+/// request, so one can be kept by the pass or sent to another thread.
+#[derive(Clone, Default)]
+pub struct Damage {
+    requested: Arc<AtomicBool>,
+    /// Wakes the event loop; `None` when headless.
+    proxy: Option<EventLoopProxy<()>>,
+}
+
+impl Damage {
+    pub(crate) fn new(proxy: Option<EventLoopProxy<()>>) -> Self {
+        Self { requested: Default::default(), proxy }
+    }
+
+    /// Schedules a frame, waking the event loop if it is idle.
+    pub fn request_frame(&self) {
+        if !self.requested.swap(true, Ordering::AcqRel) {
+            if let Some(proxy) = &self.proxy {
+                let _ = proxy.send_event(());
+            }
+        }
+    }
+
+    /// Whether a frame was requested since the last call.
+    pub(crate) fn take(&self) -> bool {
+        self.requested.swap(false, Ordering::AcqRel)
+    }
+}
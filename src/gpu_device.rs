@@ -1,47 +1,102 @@
-use std::cell::UnsafeCell;
-use std::ops::Deref;
-use lazy_static::lazy_static;
-use wgpu::{ShaderModule, ShaderModuleDescriptor, ShaderSource};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime};
+use wgpu::{ErrorFilter, ShaderModule, ShaderModuleDescriptor, ShaderSource};
 use crate::load_file;
 
-pub struct UnsafeGuaranteeLocal<T>(UnsafeCell<T>);
+/// Shaders built into the binary, used unless the dev mode below is enabled.
+const SHADER_SOURCE: &str = include_str!("../shaders.wgsl");
 
-impl<T> UnsafeGuaranteeLocal<T> {
-    fn new(t: T) -> Self {
-        Self(UnsafeCell::new(t))
+/// Setting this variable to the path of `shaders.wgsl` loads the shaders from there instead, and
+/// reloads them whenever the file changes.
+pub const SHADER_PATH_VAR: &str = "TWODR_SHADER_PATH";
+
+/// The device and queue everything is created and uploaded with, and the compiled shaders.
+/// `State` and its passes share it through an `Arc`.
+pub struct GpuContext {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    shader: RwLock<ShaderModule>,
+}
+
+impl GpuContext {
+    /// Compiles the shaders for `device`. In dev mode they are read from disk, falling back to
+    /// the built-in ones if the file can't be read or doesn't validate.
+    pub fn new(device: wgpu::Device, queue: wgpu::Queue, watcher: Option<&mut ShaderWatcher>) -> Arc<Self> {
+        let module = watcher
+            .and_then(|watcher| watcher.poll())
+            .and_then(|source| compile_shader(&device, &source))
+            .unwrap_or_else(|| compile_shader(&device, SHADER_SOURCE).unwrap());
+        Arc::new(Self {
+            device,
+            queue,
+            shader: RwLock::new(module),
+        })
     }
-    fn get_mut(&self) -> &mut T {
-        unsafe { &mut *(self.0.get() as *mut T) }
+
+    /// Compiles `source`, logging validation errors instead of panicking on them.
+    pub fn compile_shader(&self, source: &str) -> Option<ShaderModule> {
+        compile_shader(&self.device, source)
     }
-}
 
-impl<T> Deref for UnsafeGuaranteeLocal<T> {
-    type Target = T;
+    /// The current shader module. Don't hold on to it across `set_shader`.
+    pub fn shader(&self) -> RwLockReadGuard<'_, ShaderModule> {
+        self.shader.read().unwrap()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.0.get() }
+    /// Replaces the module `shader` returns. Pipelines built from the old one keep working.
+    pub fn set_shader(&self, module: ShaderModule) {
+        *self.shader.write().unwrap() = module;
     }
 }
-lazy_static! {
-    pub static ref DEVICE: UnsafeGuaranteeLocal<Option<wgpu::Device>> = UnsafeGuaranteeLocal::new(None);
-    pub static ref SHADER: ShaderModule = DEVICE.as_ref().unwrap().create_shader_module(&ShaderModuleDescriptor {
-            label: Some("Shader Module"),
-            source: ShaderSource::Wgsl(load_file("/home/henry/twodr/shaders.wgsl").into()),
+
+fn compile_shader(device: &wgpu::Device, source: &str) -> Option<ShaderModule> {
+    device.push_error_scope(ErrorFilter::Validation);
+    let module = device.create_shader_module(&ShaderModuleDescriptor {
+        label: Some("Shader Module"),
+        source: ShaderSource::Wgsl(source.into()),
     });
+    match pollster::block_on(device.pop_error_scope()) {
+        Some(e) => {
+            log::error!("Shader compilation failed: {}", e);
+            None
+        }
+        None => Some(module),
+    }
 }
 
-pub fn init_device(d: wgpu::Device) -> &'static wgpu::Device {
-    *DEVICE.get_mut() = Some(d);
-    DEVICE.as_ref().unwrap()
+/// Polls the modification time of the shader file in dev mode.
+pub struct ShaderWatcher {
+    path: String,
+    modified: Option<SystemTime>,
+    last_poll: Option<Instant>,
 }
 
-pub fn device() -> &'static wgpu::Device {
-    DEVICE.as_ref().unwrap()
-}
-pub fn shader() -> &'static ShaderModule {
-    &SHADER
-}
+impl ShaderWatcher {
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
 
-unsafe impl<T> Send for UnsafeGuaranteeLocal<T> {}
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var(SHADER_PATH_VAR).ok()?;
+        log::info!("Watching {} for shader changes", path);
+        Some(Self { path, modified: None, last_poll: None })
+    }
 
-unsafe impl<T> Sync for UnsafeGuaranteeLocal<T> {}
+    /// The new shader source if the file changed since the last call.
+    pub fn poll(&mut self) -> Option<String> {
+        if self.last_poll.is_some_and(|t| t.elapsed() < Self::POLL_INTERVAL) {
+            return None;
+        }
+        self.last_poll = Some(Instant::now());
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        match load_file(&self.path) {
+            Ok(source) => Some(source),
+            Err(e) => {
+                log::error!("{}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use bytemuck::{Pod, Zeroable};
+use image::RgbaImage;
+use lazy_static::lazy_static;
+use slotmap::{DefaultKey, SlotMap};
+use wgpu::{BlendState, Extent3d, ImageCopyTexture, ImageDataLayout, Origin3d, TextureFormat, VertexAttribute};
+
+use crate::basic_render_state::BasicRenderState;
+use crate::drawrects::{Batch, ClipRect, Instances};
+use crate::gpu_device::GpuContext;
+use crate::renderable::InstancedPass;
+
+lazy_static! {
+    pub static ref IMAGE_INSTANCE_ATTRIBUTES: [VertexAttribute; 4] =  wgpu::vertex_attr_array![0 => Float32x4, 1 => Unorm16x4, 2 => Uint32, 3 => Float32];
+}
+
+pub type ImageInstances = Instances<ImageInstance>;
+
+impl ImageInstances {
+    pub fn new(gpu: &Arc<GpuContext>) -> Self {
+        Self::new_with_layout(gpu, &*IMAGE_INSTANCE_ATTRIBUTES)
+    }
+}
+
+/// One image drawn by the image pipeline.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq)]
+pub struct ImageInstance {
+    /// Left, bottom, right and top edge in pixels.
+    rect: [f32; 4],
+    /// Atlas region as left, top, right and bottom, as fractions of the atlas size.
+    tex_rect: [u16; 4],
+    tint: [u8; 4],
+    opacity: f32,
+}
+
+unsafe impl Pod for ImageInstance {}
+
+unsafe impl Zeroable for ImageInstance {}
+
+/// Where an image was placed in the atlas, in atlas pixels.
+#[derive(Debug, Clone, Copy)]
+pub struct AtlasImage {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An image placed on screen, in the same y-up pixel coordinates as rects.
+#[derive(Debug)]
+pub struct ImageObject {
+    /// Key returned by `ImagePass::add_image`.
+    pub image: DefaultKey,
+    /// Bottom left corner.
+    pub position: (f32, f32),
+    /// Drawn size in pixels; the image's own size when `None`.
+    pub size: Option<(f32, f32)>,
+    /// Multiplied with the image's colours, sRGB encoded like every other colour.
+    pub tint: [u8; 4],
+    pub opacity: f32,
+    pub clip: Option<ClipRect>,
+}
+
+impl ImageObject {
+    pub(crate) fn new(image: DefaultKey, position: (f32, f32)) -> Self {
+        Self {
+            image,
+            position,
+            size: None,
+            tint: [255, 255, 255, 255],
+            opacity: 1.0,
+            clip: None,
+        }
+    }
+}
+
+/// A row of the atlas. Images are appended left to right; the row is emptied again once every
+/// image in it was removed.
+#[derive(Debug)]
+struct Shelf {
+    y: u32,
+    height: u32,
+    /// Left edge of the free space.
+    end: u32,
+    images: usize,
+}
+
+/// Packs images into rows of a square atlas.
+#[derive(Debug, Default)]
+struct Shelves {
+    shelves: Vec<Shelf>,
+}
+
+impl Shelves {
+    /// Finds room for a `width` by `height` image, reusing the first row it fits in before
+    /// opening a new one.
+    fn allocate(&mut self, width: u32, height: u32, size: u32, gap: u32) -> Option<(u32, u32)> {
+        let fits = |shelf: &Shelf| height <= shelf.height && shelf.end + width <= size;
+        let shelf = match self.shelves.iter().position(fits) {
+            Some(i) => &mut self.shelves[i],
+            None => {
+                let y = self.shelves.last().map_or(0, |s| s.y + s.height + gap);
+                if width > size || y + height > size {
+                    return None;
+                }
+                self.shelves.push(Shelf { y, height, end: 0, images: 0 });
+                self.shelves.last_mut().unwrap()
+            }
+        };
+        let x = shelf.end;
+        shelf.end += width + gap;
+        shelf.images += 1;
+        Some((x, shelf.y))
+    }
+
+    /// Frees an image allocated at row `y`. Free rows at the end are dropped so they can be
+    /// reopened with a different height.
+    fn free(&mut self, y: u32) {
+        if let Some(shelf) = self.shelves.iter_mut().find(|s| s.y == y) {
+            shelf.images -= 1;
+            if shelf.images == 0 {
+                shelf.end = 0;
+            }
+        }
+        while matches!(self.shelves.last(), Some(s) if s.images == 0) {
+            self.shelves.pop();
+        }
+    }
+}
+
+/// Draws images from one atlas texture. Images are packed in rows as they are added, and a row's
+/// space is reused once all of its images were removed. The atlas texture is only allocated when
+/// the first image is added.
+pub struct ImagePass {
+    state: BasicRenderState,
+    verts: ImageInstances,
+    batches: Vec<Batch>,
+    pub images: SlotMap<DefaultKey, AtlasImage>,
+    pub objects: SlotMap<DefaultKey, ImageObject>,
+    shelves: Shelves,
+    atlas_allocated: bool,
+}
+
+impl ImagePass {
+    const ATLAS_SIZE: u32 = 2048;
+    /// Empty pixels between images so filtering never picks up a neighbour.
+    const GAP: u32 = 1;
+
+    pub fn new(gpu: &Arc<GpuContext>, format: TextureFormat) -> Self {
+        let verts = ImageInstances::new(gpu);
+        Self {
+            state: BasicRenderState::new(gpu, "image", Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            }, verts.layout.clone(), BlendState::ALPHA_BLENDING, format),
+            verts,
+            batches: Vec::new(),
+            images: Default::default(),
+            objects: Default::default(),
+            shelves: Default::default(),
+            atlas_allocated: false,
+        }
+    }
+
+    /// Copies `image` into the atlas. Returns `None` when there is no room left for it.
+    pub fn add_image(&mut self, image: &RgbaImage) -> Option<DefaultKey> {
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let (x, y) = self.shelves.allocate(width, height, Self::ATLAS_SIZE, Self::GAP)?;
+        if !self.atlas_allocated {
+            self.state.replace_texture(Extent3d {
+                width: Self::ATLAS_SIZE,
+                height: Self::ATLAS_SIZE,
+                depth_or_array_layers: 1,
+            });
+            self.atlas_allocated = true;
+        }
+        // The texture is BGRA, so the channels are swapped back when sampling
+        self.state.gpu.queue.write_texture(ImageCopyTexture {
+            texture: &self.state.texture,
+            mip_level: 0,
+            origin: Origin3d { x, y, z: 0 },
+            aspect: Default::default(),
+        }, image.as_raw(), ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(NonZeroU32::try_from(width * 4).unwrap()),
+            rows_per_image: Some(NonZeroU32::try_from(height).unwrap()),
+        }, Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        });
+        Some(self.images.insert(AtlasImage { x, y, width, height }))
+    }
+
+    /// Removes an image and every object showing it. Its row of the atlas is reused once the
+    /// row holds no other image.
+    pub fn remove_image(&mut self, key: DefaultKey) -> Option<AtlasImage> {
+        let image = self.images.remove(key)?;
+        self.objects.retain(|_, object| object.image != key);
+        self.shelves.free(image.y);
+        Some(image)
+    }
+
+    pub fn add_object(&mut self, object: ImageObject) -> DefaultKey {
+        self.objects.insert(object)
+    }
+
+    pub fn remove_object(&mut self, key: DefaultKey) -> Option<ImageObject> {
+        self.objects.remove(key)
+    }
+}
+
+impl InstancedPass for ImagePass {
+    type Instance = ImageInstance;
+
+    /// One instance per object, with the image's place in the atlas as unorm texture
+    /// coordinates.
+    fn upload_data(&mut self) {
+        let mut instances = Vec::with_capacity(self.objects.len());
+        self.batches.clear();
+        let atlas = Self::ATLAS_SIZE as f32;
+        let to_unorm = |t: u32| (t as f32 / atlas * u16::MAX as f32).round() as u16;
+        for object in self.objects.values() {
+            let image = match self.images.get(object.image) {
+                Some(image) => image,
+                None => continue,
+            };
+            let (w, h) = object.size.unwrap_or((image.width as f32, image.height as f32));
+            let (x, y) = object.position;
+            Batch::push(&mut self.batches, instances.len()..instances.len() + 1, object.clip);
+            instances.push(ImageInstance {
+                rect: [x, y, x + w, y + h],
+                tex_rect: [to_unorm(image.x), to_unorm(image.y), to_unorm(image.x + image.width), to_unorm(image.y + image.height)],
+                tint: object.tint,
+                opacity: object.opacity,
+            });
+        }
+        self.verts.set_instances(0, &instances);
+        self.verts.truncate(instances.len());
+    }
+
+    fn parts(&self) -> (&BasicRenderState, &ImageInstances, &[Batch]) {
+        (&self.state, &self.verts, &self.batches)
+    }
+
+    fn parts_mut(&mut self) -> (&mut BasicRenderState, &mut ImageInstances, &[Batch]) {
+        (&mut self.state, &mut self.verts, &self.batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shelves_pack_rows() {
+        let mut shelves = Shelves::default();
+        assert_eq!(shelves.allocate(60, 10, 100, 1), Some((0, 0)));
+        assert_eq!(shelves.allocate(30, 8, 100, 1), Some((61, 0)));
+        // Too wide for the first row, so a second one opens below it
+        assert_eq!(shelves.allocate(30, 5, 100, 1), Some((0, 11)));
+        // Too tall for either row
+        assert_eq!(shelves.allocate(10, 20, 100, 1), Some((0, 17)));
+        assert_eq!(shelves.allocate(10, 80, 100, 1), None);
+        assert_eq!(shelves.allocate(101, 1, 100, 1), None);
+    }
+
+    #[test]
+    fn shelves_reclaim_rows() {
+        let mut shelves = Shelves::default();
+        let full = |s: &mut Shelves| {
+            let mut n = 0;
+            while s.allocate(50, 50, 100, 0).is_some() {
+                n += 1;
+            }
+            n
+        };
+        assert_eq!(full(&mut shelves), 4);
+        // A row is only reused once every image in it is gone
+        shelves.free(0);
+        assert_eq!(full(&mut shelves), 0);
+        shelves.free(0);
+        assert_eq!(shelves.allocate(50, 50, 100, 0), Some((0, 0)));
+        // Emptied rows at the end can reopen with another height
+        shelves.free(50);
+        shelves.free(50);
+        assert_eq!(shelves.allocate(100, 40, 100, 0), Some((0, 50)));
+        assert_eq!(shelves.allocate(100, 10, 100, 0), Some((0, 90)));
+    }
+}
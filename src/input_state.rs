@@ -5,7 +5,10 @@ pub struct InputState {
     pub mouse_pos: (i32, i32),
     pub modifiers: ModifiersState,
     pub key_buffer: Vec<char>,
-    pub scroll: (i32, i32),
+    /// Wheel movement towards older output since the last `take_scroll`, from wheels that
+    /// report lines and from touchpads that report pixels.
+    pub scroll_lines: f32,
+    pub scroll_pixels: f32,
 }
 
 impl InputState {
@@ -13,11 +16,10 @@ impl InputState {
         match event {
             WindowEvent::MouseWheel { delta, phase, .. } => {
                 println!("Scrolling {:?}", delta);
-                let delta = match delta {
-                    MouseScrollDelta::LineDelta(x, y) => (*x as i32, *y as i32),
-                    MouseScrollDelta::PixelDelta(winit::dpi::PhysicalPosition { x, y }) => ((*x) as i32, (*y) as i32)
-                };
-                self.scroll = delta;
+                match delta {
+                    MouseScrollDelta::LineDelta(_, y) => self.scroll_lines += y,
+                    MouseScrollDelta::PixelDelta(position) => self.scroll_pixels += position.y as f32,
+                }
             }
             WindowEvent::CursorMoved { position, .. } => {
                 self.mouse_pos = (position.x as i32, position.y as i32);
@@ -37,4 +39,44 @@ impl InputState {
             _ => {}
         }
     }
+
+    /// Pixels to move a viewport down by for the wheel movement since the last call. Wheel
+    /// deltas are positive towards the top, so they move the viewport up.
+    pub fn take_scroll(&mut self, line_height: i32) -> i32 {
+        let up = self.scroll_lines * line_height as f32 + self.scroll_pixels;
+        self.scroll_lines = 0.0;
+        self.scroll_pixels = 0.0;
+        -up.round() as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::dpi::PhysicalPosition;
+    use winit::event::{DeviceId, TouchPhase};
+
+    use super::*;
+
+    fn wheel(delta: MouseScrollDelta) -> WindowEvent<'static> {
+        #[allow(deprecated)]
+        WindowEvent::MouseWheel {
+            device_id: unsafe { DeviceId::dummy() },
+            delta,
+            phase: TouchPhase::Moved,
+            modifiers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn wheel_scrolls_by_lines() {
+        let mut input = InputState::default();
+        // One notch towards the top moves the viewport up a line
+        input.process_input(&wheel(MouseScrollDelta::LineDelta(0.0, 1.0)));
+        assert_eq!(input.take_scroll(16), -16);
+        assert_eq!(input.take_scroll(16), 0);
+
+        input.process_input(&wheel(MouseScrollDelta::LineDelta(0.0, -2.0)));
+        input.process_input(&wheel(MouseScrollDelta::PixelDelta(PhysicalPosition::new(0.0, 5.0))));
+        assert_eq!(input.take_scroll(16), 27);
+    }
 }
\ No newline at end of file
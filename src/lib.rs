@@ -0,0 +1,760 @@
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+use std::iter;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Instant;
+
+use image::{Rgba, RgbaImage};
+use slotmap::{DefaultKey, SlotMap};
+use wgpu::{AddressMode, BlendState, BufferAddress, BufferDescriptor, BufferUsages, Color, COPY_BYTES_PER_ROW_ALIGNMENT, Device, Extent3d, Features, FilterMode, ImageCopyBuffer, ImageDataLayout, Maintain, MapMode, RenderPipeline, Sampler, SamplerDescriptor, ShaderModule, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages};
+use winit::window::Window;
+
+use basic_render_state::{BasicRenderState, Uniforms};
+use images::ImagePass;
+use lines::LinePass;
+use frame_pacing::Damage;
+use renderable::{all_passes, FrameContext, InstancedPass, PassId, Renderable};
+use terminal::Terminal;
+use text::TextPass;
+
+use crate::error::{Error, Result};
+use crate::drawrects::{Batch, ClipRect, RectInstance, RectInstances};
+use crate::gpu_device::{GpuContext, ShaderWatcher};
+
+mod ansi;
+pub mod gpu_device;
+mod fps_counter;
+pub mod frame_pacing;
+pub mod drawrects;
+pub mod error;
+pub mod terminal;
+pub mod basic_render_state;
+pub mod text;
+mod input_state;
+mod bezier;
+pub mod lines;
+pub mod images;
+pub mod renderable;
+pub mod scrollback;
+mod vt;
+
+pub fn load_file(path: &str) -> Result<String> {
+    let mut buf = String::new();
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| Error::Io(path.to_owned(), e))?;
+    Ok(buf)
+}
+
+/// Where `State::render` presents frames.
+enum RenderTarget {
+    Window(wgpu::Surface),
+    /// No window; frames are only produced by `State::render_to_image`.
+    Headless,
+}
+
+/// Bytes per row of a texture copy `width` pixels wide, padded to the 256 bytes copies require.
+fn padded_row_bytes(width: u32) -> u32 {
+    (width * 4).div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT) * COPY_BYTES_PER_ROW_ALIGNMENT
+}
+
+/// An image from rows of 4 byte pixels laid out as `padded_row_bytes` requires. `bgra` swaps the
+/// red and blue channels back.
+fn unpad_rows(data: &[u8], width: u32, height: u32, bgra: bool) -> RgbaImage {
+    let mut image = RgbaImage::new(width, height);
+    for (y, row) in data.chunks(padded_row_bytes(width) as usize).take(height as usize).enumerate() {
+        for (x, p) in row[..width as usize * 4].chunks(4).enumerate() {
+            let pixel = if bgra { [p[2], p[1], p[0], p[3]] } else { [p[0], p[1], p[2], p[3]] };
+            image.put_pixel(x as u32, y as u32, Rgba(pixel));
+        }
+    }
+    image
+}
+
+struct State {
+    target: RenderTarget,
+    /// Format of the surface, or of the offscreen texture when headless.
+    format: TextureFormat,
+    present_mode: wgpu::PresentMode,
+    /// Samples per pixel. Above 1, passes draw into `msaa_target`, which is resolved into the
+    /// frame.
+    sample_count: u32,
+    /// Highest sample count `set_sample_count` accepts on this adapter, 4 or 8.
+    max_sample_count: u32,
+    msaa_target: Option<wgpu::TextureView>,
+    gpu: Arc<GpuContext>,
+    size: winit::dpi::PhysicalSize<u32>,
+    // The built-in passes stay typed fields, since `Terminal` edits their objects every frame;
+    // `all_passes` lists them together with the boxed application passes for drawing.
+    tp: TextPass,
+    rp: RectPass,
+    lp: LinePass,
+    ip: ImagePass,
+    /// Passes added by the application, see `add_pass`, indexed by `PassId::Custom`. Removed
+    /// passes leave `None` so the ids of the others stay valid.
+    custom_passes: Vec<Option<Box<dyn Renderable>>>,
+    pass_order: Vec<PassId>,
+    /// Frames requested by the passes; `Terminal` gives it a proxy to wake its event loop.
+    damage: Damage,
+    /// Set in shader dev mode, see `gpu_device::SHADER_PATH_VAR`.
+    shader_watcher: Option<ShaderWatcher>,
+}
+
+struct RectPass {
+    state: BasicRenderState,
+    verts: RectInstances,
+    batches: Vec<Batch>,
+    rects: SlotMap<DefaultKey, RectObject>,
+}
+
+impl RectPass {
+    fn new(gpu: &Arc<GpuContext>, format: TextureFormat) -> Self {
+        let verts = RectInstances::new(gpu);
+        Self {
+            state: BasicRenderState::new(gpu, "rect", Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+
+            }, verts.layout.clone(), BlendState::ALPHA_BLENDING, format),
+            verts,
+            batches: Vec::new(),
+            rects: Default::default(),
+        }
+    }
+
+    fn add_rect(&mut self, ro: RectObject) -> DefaultKey {
+        self.rects.insert(ro)
+    }
+
+    fn remove_rect(&mut self, key: DefaultKey) -> Option<RectObject> {
+        self.rects.remove(key)
+    }
+}
+
+impl InstancedPass for RectPass {
+    type Instance = RectInstance;
+
+    /// Rects are drawn in slot order, each shadow just before its rect. Neighbours with the same
+    /// clip share a batch.
+    fn upload_data(&mut self) {
+        let mut instances = Vec::with_capacity(self.rects.len());
+        self.batches.clear();
+        for r in self.rects.values() {
+            let start = instances.len();
+            r.push_instances(&mut instances);
+            Batch::push(&mut self.batches, start..instances.len(), r.clip);
+        }
+        self.verts.set_instances(0, &instances);
+        self.verts.truncate(instances.len());
+    }
+
+    fn parts(&self) -> (&BasicRenderState, &RectInstances, &[Batch]) {
+        (&self.state, &self.verts, &self.batches)
+    }
+
+    fn parts_mut(&mut self) -> (&mut BasicRenderState, &mut RectInstances, &[Batch]) {
+        (&mut self.state, &mut self.verts, &self.batches)
+    }
+}
+
+/// A filled rectangle in y-up pixel coordinates, `(x, y)` being its bottom left corner.
+/// Colours are sRGB encoded.
+#[derive(Debug, Default, Clone)]
+pub struct RectObject {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    pub color: [u8; 4],
+    /// Corner radius in pixels, limited to half the shorter side.
+    pub radius: f32,
+    /// Drawn inside the edge, so it doesn't change the rect's size.
+    pub border_width: f32,
+    pub border_color: [u8; 4],
+    /// Fades from `color` to the gradient's colour across the rect.
+    pub gradient: Option<Gradient>,
+    pub shadow: Option<Shadow>,
+    pub clip: Option<ClipRect>,
+}
+
+impl RectObject {
+    pub fn new(x: u32, y: u32, w: u32, h: u32, color: [u8; 4]) -> Self {
+        Self { x, y, w, h, color, ..Default::default() }
+    }
+
+    pub fn with_radius(self, radius: f32) -> Self {
+        Self { radius, ..self }
+    }
+
+    pub fn with_border(self, width: f32, color: [u8; 4]) -> Self {
+        Self { border_width: width, border_color: color, ..self }
+    }
+
+    pub fn with_gradient(self, color: [u8; 4], angle: f32) -> Self {
+        Self { gradient: Some(Gradient { color, angle }), ..self }
+    }
+
+    pub fn with_shadow(self, color: [u8; 4], offset: (f32, f32), blur: f32) -> Self {
+        Self { shadow: Some(Shadow { color, offset, blur }), ..self }
+    }
+
+    pub fn with_clip(self, clip: ClipRect) -> Self {
+        Self { clip: Some(clip), ..self }
+    }
+
+    /// The instances the rect pipeline draws for this rect: its shadow, if any, then the rect.
+    fn push_instances(&self, instances: &mut Vec<RectInstance>) {
+        let rect = [self.x as f32, self.y as f32, (self.x + self.w) as f32, (self.y + self.h) as f32];
+        // Shadows go first so the rect is drawn over its own shadow
+        if let Some(shadow) = self.shadow {
+            let (dx, dy) = shadow.offset;
+            instances.push(RectInstance {
+                rect: [rect[0] + dx, rect[1] + dy, rect[2] + dx, rect[3] + dy],
+                color: shadow.color,
+                gradient_color: shadow.color,
+                border_color: shadow.color,
+                radius: self.radius,
+                border_width: 0.0,
+                softness: shadow.blur.max(1.0),
+                gradient_dir: [1.0, 0.0],
+            });
+        }
+        let (gradient_color, angle) = self.gradient.map_or((self.color, 0.0), |g| (g.color, g.angle));
+        instances.push(RectInstance {
+            rect,
+            color: self.color,
+            gradient_color,
+            border_color: self.border_color,
+            radius: self.radius,
+            border_width: self.border_width,
+            softness: 1.0,
+            gradient_dir: [angle.cos(), angle.sin()],
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gradient {
+    pub color: [u8; 4],
+    /// Direction in radians, counter-clockwise from left to right.
+    pub angle: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadow {
+    pub color: [u8; 4],
+    /// Offset from the rect in pixels, y-up.
+    pub offset: (f32, f32),
+    /// Width of the soft edge in pixels.
+    pub blur: f32,
+}
+
+/// A rect added with `Terminal::add_rect`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RectHandle(DefaultKey);
+
+
+#[derive(Debug)]
+struct RectanglePoint<T = i32> {
+    x: T,
+    y: T,
+    x1: T,
+    y1: T,
+}
+
+impl RectanglePoint<i32> {
+    fn div_by_float(&self, fx: f64, fy: f64) -> RectanglePoint<f32> {
+        RectanglePoint { x: (self.x as f64 / fx) as f32, y: (self.y as f64 / fy) as f32, x1: (self.x1 as f64 / fx) as f32, y1: (self.y1 as f64 / fy) as f32 }
+    }
+}
+
+impl RectanglePoint<f32> {
+    fn div_by_float(&self, fx: f64, fy: f64) -> RectanglePoint<f32> {
+        RectanglePoint { x: (self.x as f64 / fx) as f32, y: (self.y as f64 / fy) as f32, x1: (self.x1 as f64 / fx) as f32, y1: (self.y1 as f64 / fy) as f32 }
+    }
+}
+
+impl<T> RectanglePoint<T> {
+    fn as_array(self) -> [T; 4] {
+        [self.x, self.y, self.x1, self.y1]
+    }
+}
+
+impl<T: Display> Display for RectanglePoint<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Rect {} {} {} {}", self.x, self.x1, self.y, self.y1))
+    }
+}
+
+
+/// Colours are given sRGB encoded, as in `RectObject::color`.
+const CLEAR_COLOR: [u8; 4] = [255, 255, 255, 255];
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn create_sampler(device: &Device) -> Sampler {
+    device.create_sampler(&SamplerDescriptor {
+        label: None,
+        address_mode_u: AddressMode::ClampToEdge,
+        address_mode_v: AddressMode::ClampToEdge,
+        address_mode_w: AddressMode::ClampToEdge,
+        mag_filter: FilterMode::Nearest,
+        min_filter: FilterMode::Nearest,
+        ..Default::default()
+    })
+}
+
+impl State {
+    fn new(window: &Window) -> Result<Self> {
+        let size = window.inner_size();
+
+        // The instance is a handle to our GPU
+        // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let surface = unsafe { instance.create_surface(window) };
+        let adapter = pollster::block_on(instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: Some(&surface),
+                force_fallback_adapter: false,
+            })).ok_or(Error::NoAdapter)?;
+        let format = surface.get_preferred_format(&adapter).ok_or(Error::UnsupportedSurface)?;
+        Self::with_adapter(adapter, RenderTarget::Window(surface), format, size)
+    }
+
+    /// A state without a window, for drawing with `render_to_image`. Falls back to a software
+    /// adapter when there is no GPU.
+    fn headless(width: u32, height: u32) -> Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let request = |force_fallback_adapter| pollster::block_on(instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter,
+            }));
+        let adapter = request(false).or_else(|| request(true)).ok_or(Error::NoAdapter)?;
+        let size = winit::dpi::PhysicalSize::new(width, height);
+        Self::with_adapter(adapter, RenderTarget::Headless, TextureFormat::Rgba8UnormSrgb, size)
+    }
+
+    fn with_adapter(adapter: wgpu::Adapter, target: RenderTarget, format: TextureFormat, size: winit::dpi::PhysicalSize<u32>) -> Result<Self> {
+        let max_sample_count = if Self::supports_8x(&adapter, format) { 8 } else { 4 };
+        let (device, queue) = pollster::block_on(adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features: Features::empty(),
+                    limits: wgpu::Limits::default(),
+                },
+                None,
+            ))?;
+
+        let mut shader_watcher = ShaderWatcher::from_env();
+        let gpu = GpuContext::new(device, queue, shader_watcher.as_mut());
+        let tp = TextPass::new(&gpu, format)?;
+        let rp = RectPass::new(&gpu, format);
+        let lp = LinePass::new(&gpu, format);
+        let ip = ImagePass::new(&gpu, format);
+        let state = Self {
+            target,
+            format,
+            present_mode: wgpu::PresentMode::Fifo,
+            sample_count: 1,
+            max_sample_count,
+            msaa_target: None,
+            gpu,
+            size,
+            tp,
+            rp,
+            lp,
+            ip,
+            custom_passes: Vec::new(),
+            pass_order: PassId::DEFAULT_ORDER.to_vec(),
+            damage: Damage::default(),
+            shader_watcher,
+        };
+        state.configure_surface();
+        Ok(state)
+    }
+
+    /// wgpu 0.12 only checks that a sample count is a power of two and can't report which
+    /// counts a format supports, so a bad count only fails inside the driver. WebGPU
+    /// guarantees 4; 8 is only trusted on hardware adapters of the native backends.
+    fn supports_8x(adapter: &wgpu::Adapter, format: TextureFormat) -> bool {
+        let info = adapter.get_info();
+        matches!(info.backend, wgpu::Backend::Vulkan | wgpu::Backend::Metal | wgpu::Backend::Dx12)
+            && matches!(info.device_type, wgpu::DeviceType::DiscreteGpu | wgpu::DeviceType::IntegratedGpu)
+            && adapter.get_texture_format_features(format).allowed_usages.contains(TextureUsages::RENDER_ATTACHMENT)
+    }
+
+    fn configure_surface(&self) {
+        if let RenderTarget::Window(surface) = &self.target {
+            let config = wgpu::SurfaceConfiguration {
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                format: self.format,
+                width: self.size.width,
+                height: self.size.height,
+                present_mode: self.present_mode,
+            };
+            surface.configure(&self.gpu.device, &config);
+        }
+    }
+
+    /// Modes the surface doesn't support fall back to `Fifo`.
+    fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        self.present_mode = present_mode;
+        self.configure_surface();
+    }
+
+    /// Reconfigures the surface for the new size. A minimized window reports a size of zero,
+    /// which the surface can't be configured with, so it is ignored.
+    fn resize(&mut self, size: winit::dpi::PhysicalSize<u32>) {
+        if size.width == 0 || size.height == 0 {
+            return;
+        }
+        self.size = size;
+        self.configure_surface();
+        self.msaa_target = self.create_msaa_target(self.sample_count);
+    }
+
+    /// Multisampled colour target the size of the frame, or `None` without multisampling.
+    fn create_msaa_target(&self, sample_count: u32) -> Option<wgpu::TextureView> {
+        if sample_count == 1 {
+            return None;
+        }
+        let texture = self.gpu.device.create_texture(&TextureDescriptor {
+            label: Some("MSAA target"),
+            size: Extent3d {
+                width: self.size.width,
+                height: self.size.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: TextureDimension::D2,
+            format: self.format,
+            usage: TextureUsages::RENDER_ATTACHMENT,
+        });
+        Some(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+    }
+
+    /// Renders with `sample_count` samples per pixel; 1 turns multisampling off. Counts other
+    /// than 1, 4 and 8, and 8 on adapters not known to support it (see `supports_8x`), are
+    /// logged and ignored.
+    fn set_sample_count(&mut self, sample_count: u32) {
+        if ![1, 4, 8].contains(&sample_count) || sample_count > self.max_sample_count {
+            log::warn!("Unsupported sample count {}", sample_count);
+            return;
+        }
+        if sample_count == self.sample_count {
+            return;
+        }
+        let gpu = self.gpu.clone();
+        gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let pipelines = self.build_pipelines(&gpu.shader(), sample_count);
+        let msaa_target = self.create_msaa_target(sample_count);
+        if let Some(e) = pollster::block_on(gpu.device.pop_error_scope()) {
+            log::error!("Failed to enable {}x multisampling: {}", sample_count, e);
+            return;
+        }
+        self.set_pipelines(pipelines);
+        self.sample_count = sample_count;
+        self.msaa_target = msaa_target;
+    }
+
+    /// Draws a frame to the window. Does nothing for a headless state.
+    fn render(&mut self) -> std::result::Result<(), wgpu::SurfaceError> {
+        let output = match &self.target {
+            RenderTarget::Window(surface) => surface.get_current_texture()?,
+            RenderTarget::Headless => return Ok(()),
+        };
+        let view = output
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        self.draw(&view);
+        output.present();
+
+        Ok(())
+    }
+
+    /// Draws a frame into an offscreen texture of the same size as the window and reads it back.
+    fn render_to_image(&mut self) -> Result<RgbaImage> {
+        let size = Extent3d {
+            width: self.size.width,
+            height: self.size.height,
+            depth_or_array_layers: 1,
+        };
+        let texture = self.gpu.device.create_texture(&TextureDescriptor {
+            label: Some("Offscreen Target"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: self.format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        });
+        self.draw(&texture.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        let padded_row_bytes = padded_row_bytes(size.width);
+        let buffer = self.gpu.device.create_buffer(&BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size: (padded_row_bytes * size.height) as BufferAddress,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.gpu.device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Readback Encoder"),
+            });
+        encoder.copy_texture_to_buffer(texture.as_image_copy(), ImageCopyBuffer {
+            buffer: &buffer,
+            layout: ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(padded_row_bytes),
+                rows_per_image: None,
+            },
+        }, size);
+        self.gpu.queue.submit(iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapped = slice.map_async(MapMode::Read);
+        self.gpu.device.poll(Maintain::Wait);
+        pollster::block_on(mapped)?;
+
+        let data = slice.get_mapped_range();
+        let bgra = matches!(self.format, TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb);
+        let image = unpad_rows(&data, size.width, size.height, bgra);
+        std::mem::drop(data);
+        buffer.unmap();
+        Ok(image)
+    }
+
+    fn is_srgb(&self) -> bool {
+        self.format.describe().srgb
+    }
+
+    /// `CLEAR_COLOR` in the encoding of the target. Clear values are written as is, so an sRGB
+    /// target needs them linear.
+    fn clear_color(&self) -> wgpu::Color {
+        let [r, g, b, a] = CLEAR_COLOR.map(|c| c as f64 / 255.0);
+        let decode = |c: f64| if self.is_srgb() { srgb_to_linear(c) } else { c };
+        wgpu::Color { r: decode(r), g: decode(g), b: decode(b), a }
+    }
+
+    fn draw(&mut self, view: &wgpu::TextureView) {
+        let target = (self.size.width, self.size.height);
+        let uniforms = Uniforms::new(target, (0.0, 0.0), self.is_srgb());
+        let clear_color = self.clear_color();
+        let State { gpu, format, sample_count, tp, rp, lp, ip, custom_passes, pass_order, damage, msaa_target, .. } = self;
+        let frame = FrameContext { uniforms: &uniforms, format: *format, sample_count: *sample_count, damage };
+        let mut slots = all_passes([rp, ip, lp, tp], custom_passes);
+        // Passes missing from the order aren't drawn, and none is drawn twice
+        let mut passes: Vec<_> = pass_order.iter().filter_map(|id| slots.get_mut(id.index())?.take()).collect();
+        for pass in &mut passes {
+            pass.prepare(&frame);
+        }
+
+        let mut encoder = gpu.device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render Encoder"),
+            });
+
+        // With multisampling the passes draw into the multisampled target, resolved into `view`
+        let (view, resolve_target) = match &*msaa_target {
+            Some(msaa) => (msaa, Some(view)),
+            None => (view, None),
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(clear_color),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        for pass in &passes {
+            pass.draw(&mut render_pass, target);
+        }
+
+        std::mem::drop(render_pass);
+
+        gpu.queue.submit(iter::once(encoder.finish()));
+        for pass in passes {
+            pass.finish();
+        }
+    }
+
+    /// Adds a pass drawn after all others, and returns its id for `set_pass_order`.
+    fn add_pass(&mut self, mut pass: Box<dyn Renderable>) -> PassId {
+        // The pass can't know the current sample count when it is created
+        pass.set_pipeline(pass.pipeline_with(&self.gpu.shader(), self.sample_count));
+        let id = PassId::Custom(self.custom_passes.len());
+        self.custom_passes.push(Some(pass));
+        self.pass_order.push(id);
+        id
+    }
+
+    /// Removes a pass added with `add_pass`. Built-in passes can't be removed, only left out of
+    /// the order.
+    fn remove_pass(&mut self, id: PassId) -> Option<Box<dyn Renderable>> {
+        let pass = match id {
+            PassId::Custom(i) => self.custom_passes.get_mut(i)?.take()?,
+            _ => return None,
+        };
+        self.pass_order.retain(|&other| other != id);
+        Some(pass)
+    }
+
+    /// Draws the passes in `order`. Passes left out are not drawn.
+    fn set_pass_order(&mut self, order: Vec<PassId>) {
+        self.pass_order = order;
+    }
+
+    fn passes_mut(&mut self) -> Vec<&mut dyn Renderable> {
+        all_passes([&mut self.rp, &mut self.ip, &mut self.lp, &mut self.tp], &mut self.custom_passes).into_iter().flatten().collect()
+    }
+
+    fn update(&mut self) {
+        if let Some(source) = self.shader_watcher.as_mut().and_then(|w| w.poll()) {
+            self.reload_shaders(&source);
+        }
+        self.tp.update();
+    }
+
+    /// Rebuilds every pipeline from `source`. If the shaders or any pipeline fail to validate,
+    /// the errors are logged and the current pipelines are kept.
+    fn reload_shaders(&mut self, source: &str) {
+        let module = match self.gpu.compile_shader(source) {
+            Some(module) => module,
+            None => return,
+        };
+        let gpu = self.gpu.clone();
+        gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let pipelines = self.build_pipelines(&module, self.sample_count);
+        if let Some(e) = pollster::block_on(gpu.device.pop_error_scope()) {
+            log::error!("Shader reload failed: {}", e);
+            return;
+        }
+        self.set_pipelines(pipelines);
+        gpu.set_shader(module);
+        log::info!("Reloaded shaders");
+    }
+
+    /// A pipeline for every pass, in the order `set_pipelines` takes them. Call inside an error
+    /// scope to find out whether they are valid.
+    fn build_pipelines(&mut self, module: &ShaderModule, sample_count: u32) -> Vec<RenderPipeline> {
+        self.passes_mut().iter().map(|pass| pass.pipeline_with(module, sample_count)).collect()
+    }
+
+    fn set_pipelines(&mut self, pipelines: Vec<RenderPipeline>) {
+        for (pass, pipeline) in self.passes_mut().into_iter().zip(pipelines) {
+            pass.set_pipeline(pipeline);
+        }
+    }
+}
+
+/// Initial size of the window. The viewport follows the window when it is resized.
+pub const WIDTH: u32 = 800;
+pub const HEIGHT: u32 = 400;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instances(rect: &RectObject) -> Vec<RectInstance> {
+        let mut instances = Vec::new();
+        rect.push_instances(&mut instances);
+        instances
+    }
+
+    #[test]
+    fn plain_rect() {
+        let red = [255, 0, 0, 255];
+        let drawn = instances(&RectObject::new(10, 20, 30, 40, red));
+        assert_eq!(drawn.len(), 1);
+        let rect = drawn[0];
+        assert_eq!(rect.rect, [10.0, 20.0, 40.0, 60.0]);
+        assert_eq!((rect.color, rect.gradient_color), (red, red));
+        assert_eq!((rect.radius, rect.border_width, rect.softness), (0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn rounded_bordered_rect() {
+        let rect = RectObject::new(0, 0, 30, 40, [255; 4]).with_radius(6.0).with_border(2.0, [0, 0, 255, 255]);
+        let rect = instances(&rect)[0];
+        assert_eq!((rect.radius, rect.border_width), (6.0, 2.0));
+        assert_eq!(rect.border_color, [0, 0, 255, 255]);
+        assert_eq!(rect.softness, 1.0);
+    }
+
+    #[test]
+    fn gradient_rect() {
+        let rect = RectObject::new(0, 0, 30, 40, [255; 4]).with_gradient([0, 0, 0, 255], std::f32::consts::FRAC_PI_2);
+        let rect = instances(&rect)[0];
+        assert_eq!((rect.color, rect.gradient_color), ([255; 4], [0, 0, 0, 255]));
+        // Bottom to top
+        let [x, y] = rect.gradient_dir;
+        assert!(x.abs() < 1e-6 && (y - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn shadowed_rect() {
+        let shadow = [0, 0, 0, 128];
+        let rect = RectObject::new(10, 20, 30, 40, [255; 4]).with_radius(4.0).with_shadow(shadow, (3.0, -5.0), 8.0);
+        let drawn = instances(&rect);
+        assert_eq!(drawn.len(), 2);
+        // The shadow is drawn first, under the rect, with the rect's corners and a wide edge
+        assert_eq!(drawn[0].rect, [13.0, 15.0, 43.0, 55.0]);
+        assert_eq!((drawn[0].color, drawn[0].gradient_color), (shadow, shadow));
+        assert_eq!((drawn[0].radius, drawn[0].softness, drawn[0].border_width), (4.0, 8.0, 0.0));
+        assert_eq!(drawn[1].rect, [10.0, 20.0, 40.0, 60.0]);
+
+        // A hard shadow still gets an antialiased edge
+        let hard = instances(&rect.with_shadow(shadow, (0.0, 0.0), 0.0));
+        assert_eq!(hard[0].softness, 1.0);
+    }
+
+    #[test]
+    fn batch_uniforms_are_translated() {
+        let uniforms = Uniforms::new((100, 50), (1.0, 2.0), false).translated((10.0, -4.0));
+        let moved = bytemuck::bytes_of(&uniforms);
+        let expected = Uniforms::new((100, 50), (11.0, -2.0), false);
+        assert_eq!(moved, bytemuck::bytes_of(&expected));
+    }
+
+    #[test]
+    fn readback_rows_are_padded() {
+        assert_eq!(padded_row_bytes(1), 256);
+        assert_eq!(padded_row_bytes(64), 256);
+        assert_eq!(padded_row_bytes(65), 512);
+        assert_eq!(padded_row_bytes(200), 1024);
+    }
+
+    #[test]
+    fn readback_drops_padding() {
+        // Three pixels wide, so each row is 12 bytes of pixels and 244 of padding
+        let mut data = vec![0xee; 256 * 2];
+        for (i, byte) in data.iter_mut().enumerate().filter(|(i, _)| i % 256 < 12) {
+            *byte = (i / 256 * 12 + i % 256) as u8;
+        }
+        let image = unpad_rows(&data, 3, 2, false);
+        assert_eq!(image.get_pixel(0, 0).0, [0, 1, 2, 3]);
+        assert_eq!(image.get_pixel(2, 1).0, [20, 21, 22, 23]);
+        let bgra = unpad_rows(&data, 3, 2, true);
+        assert_eq!(bgra.get_pixel(2, 1).0, [22, 21, 20, 23]);
+        assert!(bgra.pixels().all(|p| p.0[3] != 0xee));
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use bytemuck::{Pod, Zeroable};
+use lazy_static::lazy_static;
+use slotmap::{DefaultKey, SlotMap};
+use wgpu::{BlendState, Extent3d, TextureFormat, VertexAttribute};
+
+use crate::basic_render_state::BasicRenderState;
+use crate::drawrects::{Batch, ClipRect, Instances};
+use crate::gpu_device::GpuContext;
+use crate::renderable::InstancedPass;
+
+lazy_static! {
+    pub static ref LINE_INSTANCE_ATTRIBUTES: [VertexAttribute; 5] =  wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2, 2 => Float32, 3 => Uint32, 4 => Uint32];
+}
+
+pub type LineInstances = Instances<LineInstance>;
+
+impl LineInstances {
+    pub fn new(gpu: &Arc<GpuContext>) -> Self {
+        Self::new_with_layout(gpu, &*LINE_INSTANCE_ATTRIBUTES)
+    }
+}
+
+/// One segment of a line, drawn as a quad around it and shaded by distance to the segment.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq)]
+pub struct LineInstance {
+    /// Start and end point in pixels.
+    a: [f32; 2],
+    b: [f32; 2],
+    width: f32,
+    color: [u8; 4],
+    /// Bit 0 rounds the start, bit 1 the end; otherwise that end is cut off square.
+    round_ends: u32,
+}
+
+unsafe impl Pod for LineInstance {}
+
+unsafe impl Zeroable for LineInstance {}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LineCap {
+    /// Ends exactly at the first and last point.
+    #[default]
+    Butt,
+    /// Extends past the end points by a half circle.
+    Round,
+}
+
+/// A line through `points`, in the same y-up pixel coordinates as rects. Segments are joined
+/// with round joins. Overlapping segments are blended twice, so translucent polylines look
+/// darker at the joins.
+#[derive(Debug, Default)]
+pub struct LineObject {
+    pub points: Vec<(f32, f32)>,
+    pub width: f32,
+    pub color: [u8; 4],
+    pub cap: LineCap,
+    pub clip: Option<ClipRect>,
+}
+
+impl LineObject {
+    pub fn new(points: Vec<(f32, f32)>, width: f32, color: [u8; 4]) -> Self {
+        Self { points, width, color, ..Default::default() }
+    }
+
+    pub fn with_cap(self, cap: LineCap) -> Self {
+        Self { cap, ..self }
+    }
+
+    pub fn with_clip(self, clip: ClipRect) -> Self {
+        Self { clip: Some(clip), ..self }
+    }
+}
+
+/// A line added with `Terminal::add_line`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineHandle(pub(crate) DefaultKey);
+
+/// Draws `LineObject`s as chains of segments, each shaded by its distance to the segment.
+pub struct LinePass {
+    state: BasicRenderState,
+    verts: LineInstances,
+    batches: Vec<Batch>,
+    pub lines: SlotMap<DefaultKey, LineObject>,
+}
+
+impl LinePass {
+    pub fn new(gpu: &Arc<GpuContext>, format: TextureFormat) -> Self {
+        let verts = LineInstances::new(gpu);
+        Self {
+            state: BasicRenderState::new(gpu, "line", Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            }, verts.layout.clone(), BlendState::ALPHA_BLENDING, format),
+            verts,
+            batches: Vec::new(),
+            lines: Default::default(),
+        }
+    }
+
+    pub fn add_line(&mut self, line: LineObject) -> DefaultKey {
+        self.lines.insert(line)
+    }
+
+    pub fn remove_line(&mut self, key: DefaultKey) -> Option<LineObject> {
+        self.lines.remove(key)
+    }
+}
+
+impl InstancedPass for LinePass {
+    type Instance = LineInstance;
+
+    /// Each segment of a line is an instance of its own, so a line with n points takes n - 1.
+    /// The segments of a line stay in one batch.
+    fn upload_data(&mut self) {
+        let mut instances = Vec::new();
+        self.batches.clear();
+        for line in self.lines.values() {
+            let start = instances.len();
+            let segments = line.points.len().saturating_sub(1);
+            for (i, pair) in line.points.windows(2).enumerate() {
+                // Inner ends are always round, which joins the segments
+                let round_start = i > 0 || line.cap == LineCap::Round;
+                let round_end = i + 1 < segments || line.cap == LineCap::Round;
+                instances.push(LineInstance {
+                    a: [pair[0].0, pair[0].1],
+                    b: [pair[1].0, pair[1].1],
+                    width: line.width,
+                    color: line.color,
+                    round_ends: round_start as u32 | (round_end as u32) << 1,
+                });
+            }
+            Batch::push(&mut self.batches, start..instances.len(), line.clip);
+        }
+        self.verts.set_instances(0, &instances);
+        self.verts.truncate(instances.len());
+    }
+
+    fn parts(&self) -> (&BasicRenderState, &LineInstances, &[Batch]) {
+        (&self.state, &self.verts, &self.batches)
+    }
+
+    fn parts_mut(&mut self) -> (&mut BasicRenderState, &mut LineInstances, &[Batch]) {
+        (&mut self.state, &mut self.verts, &self.batches)
+    }
+}
+
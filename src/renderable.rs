@@ -0,0 +1,187 @@
+use bytemuck::Pod;
+use wgpu::{RenderPass, RenderPipeline, ShaderModule, TextureFormat};
+
+use crate::basic_render_state::{BasicRenderState, Uniforms};
+use crate::drawrects::{Batch, Instances};
+use crate::frame_pacing::Damage;
+
+/// The frame a pass is prepared for.
+pub struct FrameContext<'a> {
+    pub uniforms: &'a Uniforms,
+    /// Format and sample count of the target the passes draw into.
+    pub format: TextureFormat,
+    pub sample_count: u32,
+    /// Frames are only drawn when something changed, so a pass that animates requests the next
+    /// one here.
+    pub damage: &'a Damage,
+}
+
+/// A pass drawn every frame by `State`, in the order given by `State::set_pass_order`.
+pub trait Renderable {
+    /// Uploads whatever changed since the last frame. Runs before the render pass begins.
+    fn prepare(&mut self, frame: &FrameContext);
+    /// Records the draw calls. `target` is the size of the framebuffer, for scissor rects.
+    fn draw<'a>(&'a self, p: &mut RenderPass<'a>, target: (u32, u32));
+    /// Runs once the frame was submitted.
+    fn finish(&mut self) {}
+    /// The pass's pipeline built from `module`, for a target with `sample_count` samples. Used
+    /// when shaders are reloaded or multisampling changes.
+    fn pipeline_with(&self, module: &ShaderModule, sample_count: u32) -> RenderPipeline;
+    fn set_pipeline(&mut self, pipeline: RenderPipeline);
+}
+
+/// A built-in pass, drawing instances in batches with one `BasicRenderState`. `Renderable` is
+/// implemented for all of them on top of `upload_data`.
+pub(crate) trait InstancedPass {
+    type Instance: Pod + PartialEq;
+
+    /// Brings the instances and batches up to date with the pass's objects.
+    fn upload_data(&mut self);
+    fn parts(&self) -> (&BasicRenderState, &Instances<Self::Instance>, &[Batch]);
+    fn parts_mut(&mut self) -> (&mut BasicRenderState, &mut Instances<Self::Instance>, &[Batch]);
+}
+
+impl<P: InstancedPass> Renderable for P {
+    fn prepare(&mut self, frame: &FrameContext) {
+        self.upload_data();
+        let (state, verts, batches) = self.parts_mut();
+        verts.confirm_extends();
+        state.write_uniforms(frame.uniforms, batches);
+    }
+
+    fn draw<'a>(&'a self, p: &mut RenderPass<'a>, target: (u32, u32)) {
+        let (state, verts, batches) = self.parts();
+        if verts.is_empty() {
+            return;
+        }
+        p.set_pipeline(&state.render_pipeline);
+        verts.draw(p, &state.bind_group, batches, target);
+    }
+
+    fn pipeline_with(&self, module: &ShaderModule, sample_count: u32) -> RenderPipeline {
+        self.parts().0.pipeline_with(module, sample_count)
+    }
+
+    fn set_pipeline(&mut self, pipeline: RenderPipeline) {
+        self.parts_mut().0.render_pipeline = pipeline;
+    }
+}
+
+/// A pass in `State`'s draw order. The built-in passes are always there; custom ones exist from
+/// `State::add_pass` until `State::remove_pass`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PassId {
+    Rect,
+    Image,
+    Line,
+    Text,
+    /// Registered with `State::add_pass`, by index.
+    Custom(usize),
+}
+
+impl PassId {
+    /// Backgrounds first and text on top.
+    pub const DEFAULT_ORDER: [PassId; 4] = [PassId::Rect, PassId::Image, PassId::Line, PassId::Text];
+
+    /// Position in the list returned by `all_passes`.
+    pub fn index(self) -> usize {
+        match self {
+            PassId::Rect => 0,
+            PassId::Image => 1,
+            PassId::Line => 2,
+            PassId::Text => 3,
+            PassId::Custom(i) => 4 + i,
+        }
+    }
+}
+
+/// Every pass, the built-in ones in `DEFAULT_ORDER` followed by the custom ones, so that a
+/// pass is found at its `PassId::index`. Removed custom passes are `None`.
+pub fn all_passes<'a>(builtin: [&'a mut dyn Renderable; 4], custom: &'a mut [Option<Box<dyn Renderable>>]) -> Vec<Option<&'a mut dyn Renderable>> {
+    let mut passes: Vec<_> = builtin.into_iter().map(Some).collect();
+    passes.extend(custom.iter_mut().map(|p| p.as_mut().map(|p| &mut **p as &mut dyn Renderable)));
+    passes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(u32);
+
+    /// Animates for a number of frames, requesting each next one while preparing.
+    struct Animation {
+        frames_left: u32,
+    }
+
+    impl Renderable for Animation {
+        fn prepare(&mut self, frame: &FrameContext) {
+            if self.frames_left > 0 {
+                self.frames_left -= 1;
+                frame.damage.request_frame();
+            }
+        }
+
+        fn draw<'a>(&'a self, _: &mut RenderPass<'a>, _: (u32, u32)) {}
+
+        fn pipeline_with(&self, _: &ShaderModule, _: u32) -> RenderPipeline {
+            unreachable!()
+        }
+
+        fn set_pipeline(&mut self, _: RenderPipeline) {}
+    }
+
+    fn frame<'a>(uniforms: &'a Uniforms, damage: &'a Damage) -> FrameContext<'a> {
+        FrameContext { uniforms, format: TextureFormat::Bgra8UnormSrgb, sample_count: 1, damage }
+    }
+
+    impl Renderable for Counter {
+        fn prepare(&mut self, _: &FrameContext) {
+            self.0 += 1;
+        }
+
+        fn draw<'a>(&'a self, _: &mut RenderPass<'a>, _: (u32, u32)) {}
+
+        fn pipeline_with(&self, _: &ShaderModule, _: u32) -> RenderPipeline {
+            unreachable!()
+        }
+
+        fn set_pipeline(&mut self, _: RenderPipeline) {}
+    }
+
+    #[test]
+    fn passes_found_by_id() {
+        let mut builtin: [Counter; 4] = Default::default();
+        let [a, b, c, d] = &mut builtin;
+        let mut custom: Vec<Option<Box<dyn Renderable>>> = vec![Some(Box::new(Counter(10))), None, Some(Box::new(Counter(20)))];
+        let uniforms = Uniforms::new((1, 1), (0.0, 0.0), false);
+        let damage = Damage::default();
+        let frame = frame(&uniforms, &damage);
+        let mut passes = all_passes([a, b, c, d], &mut custom);
+
+        assert_eq!(passes.len(), 7);
+        assert!(passes[PassId::Custom(1).index()].is_none());
+        for id in [PassId::Line, PassId::Custom(2)] {
+            passes[id.index()].as_mut().unwrap().prepare(&frame);
+        }
+        drop(passes);
+        assert_eq!(builtin.iter().map(|c| c.0).collect::<Vec<_>>(), [0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn animated_pass_requests_frames() {
+        let uniforms = Uniforms::new((1, 1), (0.0, 0.0), false);
+        let damage = Damage::default();
+        let mut pass = Animation { frames_left: 2 };
+        let mut frames = 0;
+        loop {
+            pass.prepare(&frame(&uniforms, &damage));
+            frames += 1;
+            if !damage.take() {
+                break;
+            }
+        }
+        assert_eq!(frames, 3);
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Limits on how much output a pane keeps. Any limit that is exceeded drops the oldest lines.
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub max_lines: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_lines: Some(10_000),
+            max_bytes: None,
+            max_age: None,
+        }
+    }
+}
+
+/// Bookkeeping for the lines of a pane, used to decide how much to trim from the front of its
+/// text. Trimming only starts once a limit is exceeded by an eighth, so the cost of removing
+/// text from the front of the buffer is spread over many lines.
+#[derive(Default)]
+pub struct Scrollback {
+    pub retention: Retention,
+    /// Byte length (including the `\n`) and arrival time of every complete line, oldest first.
+    lines: VecDeque<(usize, Instant)>,
+    line_bytes: usize,
+    /// Length of the trailing line that has no `\n` yet.
+    partial: usize,
+    /// Lines dropped since the pane was last cleared.
+    pub dropped_lines: u64,
+}
+
+impl Scrollback {
+    pub fn new(retention: Retention) -> Self {
+        Self { retention, ..Default::default() }
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.line_bytes = 0;
+        self.partial = 0;
+        self.dropped_lines = 0;
+    }
+
+    /// Records text appended to the pane.
+    pub fn push(&mut self, s: &str) {
+        let now = Instant::now();
+        let mut line_start = 0;
+        for (i, _) in s.match_indices('\n') {
+            let len = self.partial + i + 1 - line_start;
+            self.lines.push_back((len, now));
+            self.line_bytes += len;
+            line_start = i + 1;
+            self.partial = 0;
+        }
+        self.partial += s.len() - line_start;
+    }
+
+    fn over_limit(&self, now: Instant, slack: bool) -> bool {
+        let with_slack = |limit: usize| if slack { limit + limit / 8 } else { limit };
+        let Retention { max_lines, max_bytes, max_age } = &self.retention;
+        max_lines.is_some_and(|max| self.lines.len() > with_slack(max))
+            || max_bytes.is_some_and(|max| self.line_bytes > with_slack(max))
+            || max_age.zip(self.lines.front()).is_some_and(|(max, (_, time))| {
+                let max = if slack { max + max / 8 } else { max };
+                now.duration_since(*time) > max
+            })
+    }
+
+    /// Number of bytes to remove from the front of the pane's text, always a whole number of
+    /// lines. Returns 0 until a limit is exceeded by the slack.
+    pub fn take_excess(&mut self, now: Instant) -> usize {
+        if !self.over_limit(now, true) {
+            return 0;
+        }
+        let mut excess = 0;
+        while !self.lines.is_empty() && self.over_limit(now, false) {
+            let (len, _) = self.lines.pop_front().unwrap();
+            self.line_bytes -= len;
+            self.dropped_lines += 1;
+            excess += len;
+        }
+        excess
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(max_lines: Option<usize>, max_bytes: Option<usize>, max_age: Option<Duration>) -> Scrollback {
+        Scrollback::new(Retention { max_lines, max_bytes, max_age })
+    }
+
+    /// Pushes `n` lines of 10 bytes each.
+    fn push_lines(scrollback: &mut Scrollback, n: usize) {
+        for _ in 0..n {
+            scrollback.push("123456789\n");
+        }
+    }
+
+    #[test]
+    fn line_limit() {
+        let mut scrollback = limited(Some(8), None, None);
+        // 8 lines plus an eighth of slack fit
+        push_lines(&mut scrollback, 9);
+        assert_eq!(scrollback.take_excess(Instant::now()), 0);
+        push_lines(&mut scrollback, 1);
+        assert_eq!(scrollback.take_excess(Instant::now()), 20);
+        assert_eq!(scrollback.dropped_lines, 2);
+        assert_eq!(scrollback.take_excess(Instant::now()), 0);
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut scrollback = limited(None, Some(80), None);
+        push_lines(&mut scrollback, 9);
+        assert_eq!(scrollback.take_excess(Instant::now()), 0);
+        scrollback.push("1\n");
+        assert_eq!(scrollback.take_excess(Instant::now()), 20);
+        assert_eq!(scrollback.dropped_lines, 2);
+    }
+
+    #[test]
+    fn age_limit() {
+        let mut scrollback = limited(None, None, Some(Duration::from_secs(8)));
+        push_lines(&mut scrollback, 3);
+        let start = Instant::now();
+        assert_eq!(scrollback.take_excess(start + Duration::from_millis(8500)), 0);
+        assert_eq!(scrollback.take_excess(start + Duration::from_secs(10)), 30);
+        assert_eq!(scrollback.dropped_lines, 3);
+    }
+
+    #[test]
+    fn slack_boundary() {
+        let mut scrollback = limited(Some(16), None, None);
+        push_lines(&mut scrollback, 18);
+        assert_eq!(scrollback.take_excess(Instant::now()), 0);
+        push_lines(&mut scrollback, 1);
+        assert_eq!(scrollback.take_excess(Instant::now()), 30);
+    }
+
+    #[test]
+    fn partial_lines() {
+        let mut scrollback = limited(Some(1), None, None);
+        scrollback.push("ab");
+        scrollback.push("c\nde\nf");
+        scrollback.push("g\n");
+        // The trailing line isn't complete, so it is never trimmed
+        scrollback.push("hij");
+        assert_eq!(scrollback.take_excess(Instant::now()), 7);
+        assert_eq!(scrollback.dropped_lines, 2);
+
+        scrollback.clear();
+        assert_eq!(scrollback.dropped_lines, 0);
+        scrollback.push("\n\n");
+        assert_eq!(scrollback.take_excess(Instant::now()), 1);
+    }
+}
@@ -4,31 +4,46 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use image::RgbaImage;
 use slotmap::DefaultKey;
+use wgpu::SurfaceError;
 use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
-use winit::event_loop::{ControlFlow, EventLoop};
+use winit::event_loop::{ControlFlow, EventLoop, EventLoopProxy};
 use winit::platform::unix::EventLoopExtUnix;
 use winit::window::{Window, WindowBuilder};
 
-use crate::{HEIGHT, RectObject, State, WIDTH};
+use crate::{Gradient, HEIGHT, RectHandle, RectObject, Shadow, State, WIDTH};
+use crate::error::Result;
+use crate::ansi::{Parser, StyledRuns, Utf8Decoder};
+use crate::scrollback::{Retention, Scrollback};
+use crate::vt::Grid;
 use crate::fps_counter::default_counter;
+use crate::gpu_device::GpuContext;
+use crate::frame_pacing::{Damage, FrameScheduler, LatencyStats};
 use crate::input_state::InputState;
-use crate::text::{TextInfo, TextObject, TextObjectHandle};
+use crate::images::ImageObject;
+use crate::lines::{LineHandle, LineObject};
+use crate::renderable::{PassId, Renderable};
+use crate::text::{TextInfo, TextObject, TextObjectHandle, TextPass, TextStyle};
 
 struct Cursor(DefaultKey);
 
 impl Cursor {
     const COLORONE: [u8; 4] = [255, 255, 255, 255];
     const COLORTWO: [u8; 4] = [60, 60, 60, 255];
-    fn update(&self, s: &mut State, text: &TextObjectHandle, time: u8) {
-        let TextInfo { max: br, .. } = text.get_stats(&s.tp).unwrap();
+    fn update(&self, s: &mut State, text: &TextObjectHandle, blink_on: bool) {
+        let TextInfo { max, caret, .. } = text.get_stats(&s.tp).unwrap();
+        let br = caret.unwrap_or(*max);
 
         // Set position to the bottom right of the text passage
-        let rect = s.rp.rects.get_mut(self.0).unwrap();
+        let rect = match s.rp.rects.get_mut(self.0) {
+            Some(rect) => rect,
+            None => return,
+        };
         rect.x = br.0 as u32 / 64;
         rect.y = br.1 as u32 / 64;
 
-        if time > u8::MAX / 2 {
+        if blink_on {
             rect.color = Self::COLORONE;
         } else {
             rect.color = Self::COLORTWO;
@@ -39,44 +54,152 @@ impl Cursor {
 pub struct Layout {
     text_key: Vec<TextObjectHandle>,
     cursor: Vec<Cursor>,
-    time: u8,
+    blink_start: Instant,
 }
 
 impl Layout {
-    fn new(v: Vec<TextObjectHandle>, state: &mut State) -> Self {
-        let mut cursors = Vec::new();
-        for _ in &v {
-            cursors.push(Cursor(state.rp.add_rect(RectObject {
-                x: 0,
-                y: 0,
-                w: 10,
-                h: state.tp.fontatl.font_height() / 64,
-                color: [100, 100, 100, 255],
-            })));
-        }
+    fn new() -> Self {
         Self {
-            text_key: v,
-            cursor: cursors,
-            time: 0,
+            text_key: Vec::new(),
+            cursor: Vec::new(),
+            blink_start: Instant::now(),
         }
     }
 
+    const BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+    fn blink_phase(&self, now: Instant) -> u128 {
+        (now - self.blink_start).as_millis() / Self::BLINK_INTERVAL.as_millis()
+    }
+
+    /// When the cursors next change colour.
+    fn next_blink(&self, now: Instant) -> Instant {
+        self.blink_start + Self::BLINK_INTERVAL * (self.blink_phase(now) + 1) as u32
+    }
+
+    fn add(&mut self, text: TextObjectHandle, state: &mut State) {
+        let clip = text.resolve(&state.tp).unwrap().clip;
+        self.cursor.push(Cursor(state.rp.add_rect(RectObject {
+            x: 0,
+            y: 0,
+            w: 10,
+            h: state.tp.fontatl.font_height() / 64,
+            color: [100, 100, 100, 255],
+            clip,
+            ..Default::default()
+        })));
+        self.text_key.push(text);
+    }
+
+    /// Removes the text at `idx` and its cursor from their passes.
+    fn remove(&mut self, idx: usize, state: &mut State) {
+        let text = self.text_key.remove(idx);
+        let cursor = self.cursor.remove(idx);
+        state.tp.remove_text(text);
+        state.rp.remove_rect(cursor.0);
+    }
+
     fn update(&mut self, s: &mut State) {
-        self.time = self.time.wrapping_add(5);
+        let blink_on = self.blink_phase(Instant::now()) % 2 == 1;
         for (text, cursor) in self.text_key.iter().zip(self.cursor.iter()) {
-            cursor.update(s, text, self.time);
+            cursor.update(s, text, blink_on);
+        }
+    }
+}
+
+
+enum PaneMode {
+    /// Append-only log; only SGR sequences are interpreted.
+    Log(StyledRuns),
+    /// Screen-oriented terminal emulation backed by a cell grid.
+    Emulator(Box<Grid>),
+}
+
+/// Escape sequence state and scrollback carried between writes to one pane.
+struct PaneState {
+    /// Holds a character split between writes.
+    decoder: Utf8Decoder,
+    parser: Parser,
+    mode: PaneMode,
+    scrollback: Scrollback,
+    /// Banner at the top of the pane shown when older output was dropped.
+    indicator: TextObjectHandle,
+}
+
+impl PaneState {
+    const INDICATOR_STYLE: TextStyle = TextStyle {
+        color: [90, 90, 90, 255],
+        background: [255, 240, 190, 255],
+        ..TextStyle::DEFAULT
+    };
+
+    fn new(text: &TextObjectHandle, tp: &mut TextPass) -> Self {
+        let to = text.resolve(tp).unwrap();
+        let mut scrollback = Scrollback::default();
+        scrollback.push(&to.render_str);
+        let indicator = TextObject { clip: to.clip, ..TextObject::new("", to.top_left, to.max_width) };
+        let indicator = tp.add_text(indicator);
+        Self {
+            decoder: Default::default(),
+            parser: Default::default(),
+            mode: PaneMode::Log(Default::default()),
+            scrollback,
+            indicator,
+        }
+    }
+
+    fn remove(self, tp: &mut TextPass) {
+        tp.remove_text(self.indicator);
+    }
+
+    /// Shows how many lines were dropped while the pane is scrolled to its oldest output.
+    fn update_indicator(&self, text: &TextObjectHandle, tp: &mut TextPass) {
+        let line_height = tp.fontatl.font_height() as i32 / 64;
+        let dropped = self.scrollback.dropped_lines;
+        let scrolled_to_top = text.resolve(tp).is_some_and(|to| to.scroll < line_height);
+        let label = if dropped > 0 && scrolled_to_top {
+            format!("\u{2026} {} earlier lines dropped", dropped)
+        } else {
+            String::new()
+        };
+        if self.indicator.resolve(tp).is_some_and(|to| to.render_str != label) {
+            let len = label.len();
+            self.indicator.update_str(tp, label);
+            self.indicator.add_span(tp, 0..len, Self::INDICATOR_STYLE);
         }
     }
 }
 
+/// Appends `t` to a log pane's text. The scrollback sees exactly the text that is appended, so
+/// the bytes it trims always end at a line break.
+fn append_log(parser: &mut Parser, runs: &mut StyledRuns, scrollback: &mut Scrollback, to: &mut TextObject, t: &str) {
+    parser.advance(runs, t);
+    for (run, style) in runs.runs.drain(..) {
+        scrollback.push(&run);
+        to.append_styled(&run, style);
+    }
+    to.trim_front(scrollback.take_excess(Instant::now()));
+}
 
 pub struct Terminal {
     s: State,
     cursor: Layout,
+    panes: Vec<PaneState>,
+    /// Lines between neighbouring panes.
+    dividers: Vec<DefaultKey>,
     input_state: InputState,
     event_loop: Option<EventLoop<()>>,
-    window: Window,
+    /// Wakes the event loop when a frame is needed, e.g. after a write from another thread.
+    /// Like `window`, `None` when headless.
+    proxy: Option<EventLoopProxy<()>>,
+    window: Option<Window>,
+    /// Time of the first key press not yet shown on screen.
     latency: Option<Instant>,
+    latency_stats: LatencyStats,
+    scheduler: FrameScheduler,
+    /// Something changed that the next frame should show.
+    frame_pending: bool,
+    last_frame: Instant,
 }
 
 unsafe impl Send for Terminal {}
@@ -85,7 +208,8 @@ pub struct TerminalWindow<'a>(usize, &'a mut Terminal);
 
 impl<'a> Write for TerminalWindow<'a> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.1.send_text(std::str::from_utf8(buf).unwrap(), self.0);
+        let text = self.1.panes[self.0].decoder.decode(buf)?;
+        self.1.send_text(&text, self.0);
         Ok(buf.len())
     }
 
@@ -101,41 +225,199 @@ impl<'a> TerminalWindow<'a> {
 }
 
 impl Terminal {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self> {
         let event_loop = EventLoop::new_any_thread();
         let window = WindowBuilder::new().with_inner_size(winit::dpi::PhysicalSize {
             width: WIDTH,
             height: HEIGHT,
-        }).build(&event_loop).unwrap();
-
-        let mut state = State::new(&window);
-        let text_key_l = state.tp.add_text(TextObject {
-            render_str: "hello world".to_string(),
-            top_left: (10, HEIGHT as i32 - 10),
-            max_width: WIDTH / 2 - 10,
-            dirty: false,
-        });
-        let text_key_r = state.tp.add_text(TextObject {
-            render_str: "hello world".to_string(),
-            top_left: (20 + WIDTH as i32 / 2, HEIGHT as i32 - 10),
-            max_width: WIDTH / 2 - 10,
-            dirty: false,
-        });
-        let cursor = Layout::new(vec![text_key_l, text_key_r], &mut state);
-        Terminal {
+        }).build(&event_loop)?;
+
+        let state = State::new(&window)?;
+        Ok(Self::with_state(state, Some((window, event_loop))))
+    }
+
+    /// A terminal without a window or event loop, drawn only by `screenshot`. Uses a software
+    /// adapter when there is no GPU.
+    pub fn headless(width: u32, height: u32) -> Result<Self> {
+        Ok(Self::with_state(State::headless(width, height)?, None))
+    }
+
+    fn with_state(mut state: State, window: Option<(Window, EventLoop<()>)>) -> Self {
+        let (window, event_loop) = window.unzip();
+        let proxy = event_loop.as_ref().map(EventLoop::create_proxy);
+        state.damage = Damage::new(proxy.clone());
+        let mut terminal = Terminal {
             s: state,
-            cursor,
+            cursor: Layout::new(),
+            panes: Vec::new(),
+            dividers: Vec::new(),
             window,
-            event_loop: Some(event_loop),
+            proxy,
+            event_loop,
             input_state: Default::default(),
             latency: Default::default(),
+            latency_stats: Default::default(),
+            scheduler: Default::default(),
+            frame_pending: true,
+            last_frame: Instant::now(),
+        };
+        terminal.open_pane();
+        terminal.open_pane();
+        terminal
+    }
+
+    const PANE_MARGIN: u32 = 10;
+    const DIVIDER_COLOR: [u8; 4] = [200, 200, 200, 255];
+
+    /// Adds a pane to the right of the others and returns its location.
+    pub fn open_pane(&mut self) -> usize {
+        let to = TextObject {
+            view_height: Some(1),
+            ..TextObject::new("hello world", (0, 0), 1)
+        };
+        let text = self.s.tp.add_text(to);
+        self.panes.push(PaneState::new(&text, &mut self.s.tp));
+        self.cursor.add(text, &mut self.s);
+        self.reflow();
+        self.damage();
+        self.panes.len() - 1
+    }
+
+    /// Removes a pane and all of its objects. Panes after it move down one location.
+    pub fn close_pane(&mut self, location: usize) {
+        self.panes.remove(location).remove(&mut self.s.tp);
+        self.cursor.remove(location, &mut self.s);
+        self.reflow();
+        self.damage();
+    }
+
+    pub fn resize(&mut self, size: winit::dpi::PhysicalSize<u32>) {
+        self.s.resize(size);
+        self.reflow();
+        self.damage();
+    }
+
+    /// Schedules a frame, waking the event loop if it is idle.
+    fn damage(&mut self) {
+        if !self.frame_pending {
+            self.frame_pending = true;
+            if let Some(proxy) = &self.proxy {
+                let _ = proxy.send_event(());
+            }
+        }
+    }
+
+    /// Lays the panes out side by side in equal columns filling the window. Emulator grids are
+    /// resized to their new pane.
+    fn reflow(&mut self) {
+        if self.panes.is_empty() {
+            return;
+        }
+        let size = self.s.size;
+        let margin = Self::PANE_MARGIN;
+        let count = self.panes.len() as u32;
+        let width = (size.width.saturating_sub(margin * (count + 1)) / count).max(1);
+        let height = size.height.saturating_sub(margin * 2).max(1);
+        let State { tp, rp, lp, .. } = &mut self.s;
+        for divider in self.dividers.drain(..) {
+            lp.remove_line(divider);
+        }
+        for i in 1..count {
+            let x = (i * (width + margin) + margin / 2) as f32;
+            let points = vec![(x, margin as f32), (x, size.height.saturating_sub(margin) as f32)];
+            self.dividers.push(lp.add_line(LineObject::new(points, 1.0, Self::DIVIDER_COLOR)));
+        }
+        let panes = self.panes.iter_mut().zip(&self.cursor.text_key).zip(&self.cursor.cursor);
+        for (i, ((pane, text), cursor)) in panes.enumerate() {
+            let top_left = ((margin + i as u32 * (width + margin)) as i32, size.height as i32 - margin as i32);
+            let to = text.resolve_mut(tp).unwrap();
+            to.top_left = top_left;
+            to.max_width = width;
+            to.view_height = Some(height);
+            // Keep each pane's text from drawing over its neighbour
+            to.clip = to.viewport();
+            let clip = to.clip;
+            if let Some(indicator) = pane.indicator.resolve_mut(tp) {
+                indicator.top_left = top_left;
+                indicator.max_width = width;
+                indicator.clip = clip;
+            }
+            if let Some(rect) = rp.rects.get_mut(cursor.0) {
+                rect.clip = clip;
+            }
+            if let PaneMode::Emulator(grid) = &mut pane.mode {
+                // Resizing resets the scroll region, so grids that still fit are left alone
+                let (cols, rows) = Self::grid_size(tp, text);
+                if grid.size() != (cols, rows) {
+                    grid.resize(cols, rows);
+                    Self::show_grid(grid, text, tp);
+                }
+            }
         }
     }
 
+    /// Columns and rows of the character grid that fits in a pane.
+    fn grid_size(tp: &TextPass, text: &TextObjectHandle) -> (usize, usize) {
+        let to = text.resolve(tp).unwrap();
+        let cols = to.max_width / tp.fontatl.cell_width();
+        let rows = to.view_height.unwrap_or(0) / (tp.fontatl.font_height() / 64);
+        (cols as usize, rows as usize)
+    }
+
+    fn show_grid(grid: &Grid, text: &TextObjectHandle, tp: &mut TextPass) {
+        let (screen, spans, caret) = grid.render();
+        let to = text.resolve_mut(tp).unwrap();
+        to.update_str(screen);
+        to.spans = spans;
+        to.caret = caret;
+    }
+
+    /// Writes `t` to a pane. In log mode SGR escape sequences become styled spans; in
+    /// emulator mode the whole screen is redrawn from the grid.
     pub fn send_text(&mut self, t: &str, location: usize) {
-        self.cursor.text_key[location].append_str(&mut self.s.tp, t);
+        self.damage();
+        let PaneState { parser, mode, scrollback, .. } = &mut self.panes[location];
+        let text = &self.cursor.text_key[location];
+        match mode {
+            PaneMode::Log(runs) => {
+                if let Some(to) = text.resolve_mut(&mut self.s.tp) {
+                    append_log(parser, runs, scrollback, to, t);
+                }
+            }
+            PaneMode::Emulator(grid) => {
+                parser.advance(grid.as_mut(), t);
+                Self::show_grid(grid, text, &mut self.s.tp);
+            }
+        }
+    }
+
+    /// Switches a pane between the append-only log and terminal emulation. The emulator
+    /// grid is sized to fill the pane.
+    pub fn set_emulation(&mut self, location: usize, enable: bool) {
+        self.damage();
+        let text = &self.cursor.text_key[location];
+        let cell_width = self.s.tp.fontatl.cell_width();
+        let (cols, rows) = Self::grid_size(&self.s.tp, text);
+        let to = text.resolve_mut(&mut self.s.tp).unwrap();
+        to.update_str(String::new());
+        to.caret = None;
+        self.panes[location].scrollback.clear();
+        self.panes[location].mode = if enable {
+            to.cell_width = Some(cell_width);
+            PaneMode::Emulator(Box::new(Grid::new(cols, rows)))
+        } else {
+            to.cell_width = None;
+            PaneMode::Log(Default::default())
+        };
+    }
+    pub fn set_retention(&mut self, location: usize, retention: Retention) {
+        self.panes[location].scrollback.retention = retention;
     }
     pub fn set_text(&mut self, t: &str, location: usize) {
+        self.damage();
+        let scrollback = &mut self.panes[location].scrollback;
+        scrollback.clear();
+        scrollback.push(t);
         self.cursor.text_key[location].update_str(&mut self.s.tp, t.to_string());
     }
     pub fn nth_window(&mut self, n: usize) -> TerminalWindow<'_> {
@@ -144,61 +426,195 @@ impl Terminal {
 
     pub fn update(&mut self) {
         if !self.input_state.key_buffer.is_empty() {
-            let as_str = String::from_iter(self.input_state.key_buffer.iter());
-            self.cursor.text_key[0].append_str(&mut self.s.tp, &as_str);
-            self.cursor.text_key[1].append_str(&mut self.s.tp, "fdsa fdlks;a j;f jsalknsa vc.mfda");
-            self.input_state.key_buffer.clear();
+            // Typed keys are echoed to the first pane; Enter starts a new line
+            let echo: String = self.input_state.key_buffer.drain(..).map(|c| if c == '\r' { '\n' } else { c }).collect();
+            if !self.panes.is_empty() {
+                self.send_text(&echo, 0);
+            }
         }
 
-        for i in &self.cursor.text_key {
-            if let Some(stats) = i.get_stats(&self.s.tp).cloned() {
-                let fh = self.s.tp.fontatl.font_height() as i32;
-                if stats.max.1 < fh {
-                    i.add_offset(&mut self.s.tp, (0, (fh - stats.max.1) / 64));
-                }
+        let dy = self.input_state.take_scroll(self.s.tp.fontatl.font_height() as i32 / 64);
+        if dy != 0 {
+            let (x, y) = self.input_state.mouse_pos;
+            let scroll_pos = (x, self.s.size.height as i32 - y);
+            let tp = &mut self.s.tp;
+            let hovered = self.cursor.text_key.iter().find(|text| {
+                text.resolve(tp).and_then(|to| to.viewport()).is_some_and(|v| v.contains(scroll_pos))
+            });
+            if let Some(text) = hovered {
+                text.scroll_by(tp, dy);
             }
         }
 
-        if self.input_state.scroll != (0, 0) {
-            let scroll_pos = self.input_state.mouse_pos;
-            if scroll_pos.0 < WIDTH as i32 / 2 {
-                self.cursor.text_key[0].add_offset(&mut self.s.tp, self.input_state.scroll);
-            } else {
-                self.cursor.text_key[1].add_offset(&mut self.s.tp, self.input_state.scroll);
+        // Age-based retention can expire lines without any new output
+        let now = Instant::now();
+        for (pane, text) in self.panes.iter_mut().zip(&self.cursor.text_key) {
+            if matches!(pane.mode, PaneMode::Log(_)) {
+                text.trim_front(&mut self.s.tp, pane.scrollback.take_excess(now));
             }
-            self.input_state.scroll = (0, 0);
+            pane.update_indicator(text, &mut self.s.tp);
         }
         self.s.update();
         self.cursor.update(&mut self.s);
     }
 
+    /// `Mailbox` and `Immediate` lower latency at the cost of tearing or wasted frames.
+    pub fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        self.s.set_present_mode(present_mode);
+    }
+
+    /// Multisample anti-aliasing with 1 (off), 4 or 8 samples per pixel. 8 is ignored on
+    /// software and GL adapters, which may not support it.
+    pub fn set_sample_count(&mut self, sample_count: u32) {
+        self.s.set_sample_count(sample_count);
+        self.damage();
+    }
+
+    /// The device, queue and shaders, for creating application passes.
+    pub fn gpu(&self) -> Arc<GpuContext> {
+        self.s.gpu.clone()
+    }
+
+    /// A handle for requesting frames, e.g. from a thread animating something. Passes get the
+    /// same one in `FrameContext`.
+    pub fn damage_handle(&self) -> Damage {
+        self.s.damage.clone()
+    }
+
+    /// Registers an application pass, drawn on top of the others until the order is changed.
+    pub fn add_pass(&mut self, pass: Box<dyn Renderable>) -> PassId {
+        self.damage();
+        self.s.add_pass(pass)
+    }
+
+    /// Unregisters a pass from `add_pass` and hands it back. Returns `None` for built-in passes,
+    /// which can only be left out of `set_pass_order`.
+    pub fn remove_pass(&mut self, id: PassId) -> Option<Box<dyn Renderable>> {
+        let pass = self.s.remove_pass(id)?;
+        self.damage();
+        Some(pass)
+    }
+
+    pub fn set_pass_order(&mut self, order: Vec<PassId>) {
+        self.s.set_pass_order(order);
+        self.damage();
+    }
+
+    /// Whether frames are started as late as possible before vblank rather than right away.
+    pub fn set_frame_pacing(&mut self, enabled: bool) {
+        self.scheduler.enabled = enabled;
+    }
+
+    /// Key press to present latency of recent frames.
+    pub fn key_latency(&self) -> &LatencyStats {
+        &self.latency_stats
+    }
+
+    /// Updates, renders and presents a frame, feeding its timing to the scheduler.
+    fn draw_frame(&mut self) {
+        let started = Instant::now();
+        self.last_frame = started;
+        // Cleared first so damage from a surface recovered during `render` schedules another frame
+        self.frame_pending = false;
+        self.update();
+        self.render();
+        let presented = Instant::now();
+        self.scheduler.frame_presented(started, presented);
+        if let Some(time) = self.latency.take() {
+            self.latency_stats.record(presented - time);
+            log::debug!("Key Latency {}", (presented - time).as_millis());
+        }
+    }
+
     pub fn render(&mut self) {
-        self.s.render().unwrap();
+        match self.s.render() {
+            Ok(()) => {}
+            // The surface no longer matches the window, e.g. after a resize
+            Err(SurfaceError::Lost | SurfaceError::Outdated) => {
+                if let Some(size) = self.window.as_ref().map(Window::inner_size) {
+                    self.resize(size);
+                }
+            }
+            Err(e) => log::error!("Render failed: {}", e),
+        }
+    }
+
+    /// Renders the current contents of all panes into an image.
+    pub fn screenshot(&mut self) -> Result<RgbaImage> {
+        self.update();
+        self.s.render_to_image()
+    }
+
+    /// Copies `image` into the image atlas so it can be shown with `show_image`. Returns `None`
+    /// when the atlas is full.
+    pub fn add_image(&mut self, image: &RgbaImage) -> Option<DefaultKey> {
+        self.s.ip.add_image(image)
+    }
+
+    /// Frees an image added with `add_image`, hiding everywhere it is shown.
+    pub fn remove_image(&mut self, image: DefaultKey) {
+        if self.s.ip.remove_image(image).is_some() {
+            self.damage();
+        }
+    }
+
+    /// Draws an image from `add_image` with its bottom left corner at `position`, in window
+    /// pixels. The same image can be shown any number of times.
+    pub fn show_image(&mut self, image: DefaultKey, position: (f32, f32)) -> DefaultKey {
+        self.damage();
+        self.s.ip.add_object(ImageObject::new(image, position))
+    }
+
+    pub fn hide_image(&mut self, key: DefaultKey) {
+        self.s.ip.remove_object(key);
+        self.damage();
+    }
+
+    /// Draws `line` over rects and images. Change it later through the handle.
+    pub fn add_line(&mut self, line: LineObject) -> LineHandle {
+        self.damage();
+        LineHandle(self.s.lp.add_line(line))
+    }
+
+    pub fn remove_line(&mut self, line: LineHandle) -> Option<LineObject> {
+        self.damage();
+        self.s.lp.remove_line(line.0)
+    }
+
+    /// Draws `rect` below lines, images and text. Change it later through the handle.
+    pub fn add_rect(&mut self, rect: RectObject) -> RectHandle {
+        self.damage();
+        RectHandle(self.s.rp.add_rect(rect))
+    }
+
+    pub fn remove_rect(&mut self, rect: RectHandle) -> Option<RectObject> {
+        self.damage();
+        self.s.rp.remove_rect(rect.0)
     }
 
     pub fn input(&mut self, event: &WindowEvent) -> bool {
         self.input_state.process_input(event);
         if matches!(event, WindowEvent::KeyboardInput {..}) {
-            self.latency = Some(Instant::now());
+            self.latency.get_or_insert_with(Instant::now);
         }
         true
     }
 
+    /// Handles window events until the window is closed. Panics for a headless terminal.
     pub fn run(se: Arc<Mutex<Option<Self>>>) {
         // let mut _l = se.lock();
         // let se = _l.as_mut().unwrap();
-        let ev = se.lock().as_mut().unwrap().as_mut().unwrap().event_loop.take().unwrap();
+        let ev = se.lock().as_mut().unwrap().as_mut().unwrap().event_loop.take().expect("headless terminal has no event loop");
         ev.run(move |event, _, control_flow| {
             let mut lock = se.lock();
             let se = lock.as_mut().unwrap().as_mut().unwrap();
             *control_flow = ControlFlow::Wait;
             log::debug!("Event {:?}", event);
-            let mut should_draw = false;
             match event {
                 Event::WindowEvent {
                     event,
                     window_id,
-                } if window_id == se.window.id() => {
+                } if se.window.as_ref().map(Window::id) == Some(window_id) => {
                     se.input(&event);
                     match event {
                         WindowEvent::CloseRequested
@@ -211,56 +627,238 @@ impl Terminal {
                             },
                             ..
                         } => *control_flow = ControlFlow::Exit,
-                        WindowEvent::KeyboardInput { .. } => should_draw = true,
+                        WindowEvent::KeyboardInput { .. }
+                        | WindowEvent::ReceivedCharacter(_)
+                        | WindowEvent::MouseWheel { .. } => se.frame_pending = true,
+                        WindowEvent::Resized(size) => se.resize(size),
+                        WindowEvent::ScaleFactorChanged { new_inner_size, .. } => se.resize(*new_inner_size),
                         _ => {}
                     }
                 }
                 Event::RedrawRequested(_) => {
-                    should_draw = true;
+                    se.frame_pending = true;
                 }
                 Event::RedrawEventsCleared => {
-                    se.window.request_redraw();
+                    // Nothing is drawn unless something changed or the cursors blink
+                    let now = Instant::now();
+                    let next_blink = se.cursor.next_blink(now);
+                    se.frame_pending |= se.cursor.blink_phase(now) != se.cursor.blink_phase(se.last_frame);
+                    se.frame_pending |= se.s.damage.take();
+                    if se.frame_pending {
+                        let start = se.scheduler.next_start(now);
+                        if now >= start {
+                            se.draw_frame();
+                            default_counter().frame();
+                            default_counter().report();
+                        } else {
+                            *control_flow = ControlFlow::WaitUntil(start);
+                        }
+                    }
+                    if !se.frame_pending {
+                        *control_flow = ControlFlow::WaitUntil(next_blink);
+                    }
                 }
                 _ => {}
             }
-            if should_draw {
-                let time = Instant::now();
-                se.update();
-                se.render();
-                // println!("Render time {}", time.elapsed().as_millis());
-
-                if let Some(time) = se.latency.take() {
-                    log::debug!("Key Latency {}", time.elapsed().as_millis());
-                }
-                default_counter().frame();
-                default_counter().report();
-            }
         });
     }
 }
 
+impl RectHandle {
+    pub fn resolve<'a>(&self, terminal: &'a Terminal) -> Option<&'a RectObject> {
+        terminal.s.rp.rects.get(self.0)
+    }
+
+    /// Schedules a frame, since any field may be changed through the reference.
+    pub fn resolve_mut<'a>(&self, terminal: &'a mut Terminal) -> Option<&'a mut RectObject> {
+        terminal.damage();
+        terminal.s.rp.rects.get_mut(self.0)
+    }
+
+    pub fn set_bounds(&self, terminal: &mut Terminal, x: u32, y: u32, w: u32, h: u32) {
+        if let Some(rect) = self.resolve_mut(terminal) {
+            (rect.x, rect.y, rect.w, rect.h) = (x, y, w, h);
+        }
+    }
+
+    pub fn set_color(&self, terminal: &mut Terminal, color: [u8; 4]) {
+        if let Some(rect) = self.resolve_mut(terminal) {
+            rect.color = color;
+        }
+    }
+
+    pub fn set_radius(&self, terminal: &mut Terminal, radius: f32) {
+        if let Some(rect) = self.resolve_mut(terminal) {
+            rect.radius = radius;
+        }
+    }
+
+    /// A `width` of 0 removes the border.
+    pub fn set_border(&self, terminal: &mut Terminal, width: f32, color: [u8; 4]) {
+        if let Some(rect) = self.resolve_mut(terminal) {
+            rect.border_width = width;
+            rect.border_color = color;
+        }
+    }
+
+    pub fn set_gradient(&self, terminal: &mut Terminal, gradient: Option<Gradient>) {
+        if let Some(rect) = self.resolve_mut(terminal) {
+            rect.gradient = gradient;
+        }
+    }
+
+    pub fn set_shadow(&self, terminal: &mut Terminal, shadow: Option<Shadow>) {
+        if let Some(rect) = self.resolve_mut(terminal) {
+            rect.shadow = shadow;
+        }
+    }
+}
+
+impl LineHandle {
+    pub fn resolve<'a>(&self, terminal: &'a Terminal) -> Option<&'a LineObject> {
+        terminal.s.lp.lines.get(self.0)
+    }
+
+    /// Schedules a frame, since any field may be changed through the reference.
+    pub fn resolve_mut<'a>(&self, terminal: &'a mut Terminal) -> Option<&'a mut LineObject> {
+        terminal.damage();
+        terminal.s.lp.lines.get_mut(self.0)
+    }
+
+    pub fn set_points(&self, terminal: &mut Terminal, points: Vec<(f32, f32)>) {
+        if let Some(line) = self.resolve_mut(terminal) {
+            line.points = points;
+        }
+    }
+
+    pub fn set_width(&self, terminal: &mut Terminal, width: f32) {
+        if let Some(line) = self.resolve_mut(terminal) {
+            line.width = width;
+        }
+    }
+
+    pub fn set_color(&self, terminal: &mut Terminal, color: [u8; 4]) {
+        if let Some(line) = self.resolve_mut(terminal) {
+            line.color = color;
+        }
+    }
+}
+
 pub fn test() {
     let t = Arc::new(Mutex::new(None));
     let t1 = t.clone();
     thread::spawn(move || {
-        *t1.lock().unwrap() = Some(Terminal::new());
+        match Terminal::new() {
+            Ok(terminal) => *t1.lock().unwrap() = Some(terminal),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
         Terminal::run(t1);
     });
     while t.lock().unwrap().is_none() {
         std::thread::sleep(Duration::from_millis(10));
     }
+    // The second pane runs the terminal emulator, so cursor movement there takes effect
+    t.lock().unwrap().as_mut().unwrap().set_emulation(1, true);
 
     let rand_str = &include_str!("../rand");
     loop {
         let mut lock = t.lock().unwrap();
         let t1 = lock.as_mut().unwrap();
         writeln!(t1.nth_window(0), "{}", &rand_str[000..050]).unwrap();
-        writeln!(t1.nth_window(1), "{}", &rand_str[100..150]).unwrap();
-        writeln!(t1.nth_window(1), "{}", &rand_str[150..200]).unwrap();
+        write!(t1.nth_window(1), "{}\r\n", &rand_str[100..150]).unwrap();
+        write!(t1.nth_window(1), "{}\r\n", &rand_str[150..200]).unwrap();
         writeln!(t1.nth_window(0), "{}", &rand_str[200..250]).unwrap();
         std::mem::drop(lock);
         std::thread::sleep(Duration::from_millis(1));
     }
     // writeln!(term.nth_window(1), "helo").unwrap();
     loop {}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CLEAR_COLOR;
+
+    #[test]
+    fn typing_trims_whole_lines() {
+        let (mut parser, mut runs) = Default::default();
+        let mut scrollback = Scrollback::new(Retention { max_lines: Some(8), ..Default::default() });
+        let mut to = TextObject::new("", (0, 0), 100);
+        let mut written = String::new();
+        for i in 0..40 {
+            // Output from the program, then a few typed keys that wrap onto the next line
+            let output = format!("\x1b[1mline {}\x1b[0m\n", i);
+            append_log(&mut parser, &mut runs, &mut scrollback, &mut to, &output);
+            written.push_str(&format!("line {}\n", i));
+            for key in ["\u{e9}", "x", "\n"].iter().take(i % 4) {
+                append_log(&mut parser, &mut runs, &mut scrollback, &mut to, key);
+                written.push_str(key);
+            }
+            let kept = written.len() - to.render_str.len();
+            assert!(written.ends_with(&to.render_str));
+            assert!(kept == 0 || written[..kept].ends_with('\n'), "trimmed to {:?}", &to.render_str[..10]);
+        }
+        assert!(scrollback.dropped_lines > 0);
+    }
+
+
+    /// Pixels darker than mid grey in the top left corner of the first pane.
+    fn dark_pixels(image: &RgbaImage) -> usize {
+        let margin = Terminal::PANE_MARGIN;
+        (margin..margin + 60).flat_map(|x| (margin..margin + 20).map(move |y| (x, y)))
+            .filter(|&(x, y)| image.get_pixel(x, y).0[1] < 128)
+            .count()
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter, run with --ignored"]
+    fn renders_pane_text() {
+        let mut terminal = Terminal::headless(200, 100).unwrap();
+        terminal.close_pane(1);
+        terminal.set_text("", 0);
+        let blank = terminal.screenshot().unwrap();
+        terminal.set_text("Hello", 0);
+        let image = terminal.screenshot().unwrap();
+
+        assert_eq!(image.dimensions(), (200, 100));
+        assert_eq!(image.get_pixel(199, 99).0, CLEAR_COLOR);
+        assert!(dark_pixels(&image) > dark_pixels(&blank) + 20);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter, run with --ignored"]
+    fn shows_and_removes_images() {
+        let mut terminal = Terminal::headless(200, 100).unwrap();
+        let red = RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]));
+        let key = terminal.add_image(&red).unwrap();
+        // In the bottom right corner, outside every pane
+        terminal.show_image(key, (196.0, 0.0));
+        let shown = terminal.screenshot().unwrap();
+        let [r, g, b, _] = shown.get_pixel(198, 98).0;
+        assert!(r > 200 && g < 50 && b < 50, "{:?}", (r, g, b));
+
+        terminal.remove_image(key);
+        let removed = terminal.screenshot().unwrap();
+        assert_eq!(removed.get_pixel(198, 98).0, CLEAR_COLOR);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter, run with --ignored"]
+    fn draws_and_updates_lines() {
+        let mut terminal = Terminal::headless(200, 100).unwrap();
+        // Along the bottom margin, below every pane
+        let line = terminal.add_line(LineObject::new(vec![(150.0, 3.0), (190.0, 3.0)], 4.0, [255, 0, 0, 255]));
+        let [r, _, b, _] = terminal.screenshot().unwrap().get_pixel(170, 97).0;
+        assert!(r > 200 && b < 50, "{:?}", (r, b));
+
+        line.set_color(&mut terminal, [0, 0, 255, 255]);
+        let [r, _, b, _] = terminal.screenshot().unwrap().get_pixel(170, 97).0;
+        assert!(r < 50 && b > 200, "{:?}", (r, b));
+
+        terminal.remove_line(line);
+        assert_eq!(terminal.screenshot().unwrap().get_pixel(170, 97).0, CLEAR_COLOR);
+    }
+}
@@ -1,37 +1,55 @@
 use std::collections::HashMap;
-use std::io::Read;
 use std::num::NonZeroU32;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::sync::Arc;
 
 use freetype::{GlyphMetrics, Library};
 use freetype::face::LoadFlag;
 use image::{Rgba, RgbaImage};
 use slotmap::{DefaultKey, SlotMap};
-use wgpu::{BlendComponent, BlendFactor, BlendOperation, BlendState, Extent3d, ImageCopyTexture, ImageDataLayout, IndexFormat, RenderPass};
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use wgpu::{BlendComponent, BlendFactor, BlendOperation, BlendState, Extent3d, ImageCopyTexture, ImageDataLayout, Origin3d, TextureFormat};
 
-use crate::{HEIGHT, RANDFILE, RectanglePoint, WIDTH};
+use crate::RectanglePoint;
 use crate::basic_render_state::BasicRenderState;
-use crate::drawrects::{FontDrawRects, FontTriangleVertex};
+use crate::error::{Error, Result};
+use crate::drawrects::{Batch, ClipRect, GlyphInstance, GlyphInstances};
+use crate::gpu_device::GpuContext;
+use crate::renderable::InstancedPass;
 
 #[derive(Debug, Clone)]
 pub struct TextInfo {
     pub min: (i32, i32),
     pub max: (i32, i32),
+    /// Pen position at `TextObject::caret`, if it has one.
+    pub caret: Option<(i32, i32)>,
 }
 
+/// Draws `TextObject`s as one quad per glyph, sampled from the font atlas.
 pub struct TextPass {
     state: BasicRenderState,
-    verts: FontDrawRects,
+    verts: GlyphInstances,
+    batches: Vec<Batch>,
     pub fontatl: FontAtlas,
     time: f32,
     text_objects: SlotMap<DefaultKey, TextObject>,
     text_info: HashMap<DefaultKey, TextInfo>,
+    cache: HashMap<DefaultKey, ObjectCache>,
     dirty: bool,
 }
 
 pub struct FontAtlas {
     img: RgbaImage,
     pub face: freetype::Face,
-    advances: Vec<GlyphInfo>,
+    glyphs: HashMap<char, GlyphInfo>,
+    fallback: GlyphInfo,
+    blank_texel: (u32, u32),
+    solid_texel: (u32, u32),
+    /// Left edge of the next glyph, top of the current row and the height of that row.
+    cursor: (u32, u32, u32),
+    /// Rows of `img` changed since they were last uploaded.
+    dirty_rows: Option<Range<u32>>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -64,8 +82,17 @@ impl GlyphInfo {
             x1: self.texture_coord.0 as f32 + (self.size.0 / 64) as f32,
         }
     }
-    fn calculate_next_origin(&self, origin: (i32, i32)) -> (i32, i32) {
-        (origin.0 + self.advance as i32, origin.1)
+    /// Places a combining mark horizontally centred over a base glyph starting at `origin`.
+    fn calculate_mark_rect_pos(&self, origin: (i32, i32), base_advance: i32) -> RectanglePoint {
+        let left_edge = origin.0 + (base_advance - self.size.0) / 2;
+        let top_edge = origin.1 + self.bearing.1;
+
+        RectanglePoint {
+            x: left_edge,
+            y: top_edge,
+            x1: left_edge + self.size.0,
+            y1: top_edge - self.size.1,
+        }
     }
     fn from_metrics(metrics: &GlyphMetrics, texture_coord: (i32, i32), texture_size: (i32, i32)) -> Self {
         Self {
@@ -77,59 +104,78 @@ impl GlyphInfo {
     }
 }
 
-fn load_font_atlas() -> FontAtlas {
-    let lib = Library::init().unwrap();
-    let mut advances = vec![GlyphInfo::default(); 127];
+/// Characters rasterized into the atlas up front; anything else is added when it is first laid
+/// out.
+const PRELOADED_CHARS: [RangeInclusive<char>; 7] = [
+    ' '..='~',
+    '\u{a0}'..='\u{17f}',
+    '\u{300}'..='\u{36f}',
+    '\u{2010}'..='\u{2027}',
+    '\u{2030}'..='\u{203a}',
+    '\u{20ac}'..='\u{20ac}',
+    // Box drawing and block elements, for full-screen programs in emulator mode
+    '\u{2500}'..='\u{259f}',
+];
+const ATLAS_SIZE: u32 = 1024;
 
-    let face = lib.new_face("/usr/share/fonts/truetype/ubuntu/Ubuntu-R.ttf", 0).unwrap();
-    face.set_char_size(16 * 64, 0, 0, 0).unwrap();
-    let mut max_height: u32 = 1;
-    let mut width: u32 = 1;
-    for i in 32..127 {
-        face.load_glyph(i, LoadFlag::DEFAULT).unwrap();
-        let glyph = face.glyph();
-        glyph.render_glyph(freetype::RenderMode::Lcd).unwrap();
-        let bitmap = glyph.bitmap();
-        width += bitmap.width() as u32 / 3 + 2;
-        max_height = max_height.max(bitmap.rows() as u32);
+/// `None` loads the font's .notdef glyph.
+fn render_glyph(face: &freetype::Face, c: Option<char>) -> Result<&freetype::GlyphSlot> {
+    match c {
+        Some(c) => face.load_char(c as usize, LoadFlag::DEFAULT)?,
+        None => face.load_glyph(0, LoadFlag::DEFAULT)?,
     }
-    let mut image: RgbaImage = RgbaImage::new(width, max_height + 2);
-    let mut next_x: u32 = 0;
-    for i in 32..127 {
-        face.load_char(i, LoadFlag::DEFAULT).unwrap();
-        let glyph = face.glyph();
-        glyph.render_glyph(freetype::RenderMode::Lcd).unwrap();
-        let metrics = glyph.metrics();
+    let glyph = face.glyph();
+    glyph.render_glyph(freetype::RenderMode::Lcd)?;
+    Ok(glyph)
+}
 
-        let bitmap = glyph.bitmap();
-        let bitmap_buf = bitmap.buffer();
-        let width = bitmap.width().abs() as u32 / 3;
-        let height = bitmap.rows() as u32;
+/// Tried in order; the first one that exists is used.
+const FONT_PATHS: [&str; 2] = [
+    "/usr/share/fonts/truetype/ubuntu/Ubuntu-R.ttf",
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+];
 
-        if !(width == 0 || height == 0) {
-            for y in 0..height {
-                let start = y * bitmap.pitch() as u32;
-                let end = start + bitmap.width() as u32;
-                for x in (start..end).step_by(3) {
-                    let xi = x as usize;
-                    let xglobal = (x - start) / 3 + next_x;
-                    let pixel = Rgba([bitmap_buf[xi], bitmap_buf[xi + 1], bitmap_buf[xi + 2], 255]);
-                    image.put_pixel(xglobal, y, pixel);
-                }
-            }
-        }
-        advances[i] = GlyphInfo::from_metrics(&metrics, (next_x as i32, 0), (width as i32, height as i32));
-        next_x += width + 2;
+fn load_font_atlas() -> Result<FontAtlas> {
+    let lib = Library::init()?;
+
+    let path = FONT_PATHS.iter().find(|path| Path::new(path).exists()).unwrap_or(&FONT_PATHS[0]);
+    let face = lib.new_face(path, 0).map_err(|e| match e {
+        freetype::Error::CannotOpenResource => Error::FontNotFound(path.to_string()),
+        e => Error::Font(e),
+    })?;
+    face.set_char_size(16 * 64, 0, 0, 0)?;
+
+    // The two rightmost columns are reserved: a fully covered one for underlines and an empty
+    // one so background quads can sample zero coverage.
+    let mut image = RgbaImage::new(ATLAS_SIZE, ATLAS_SIZE);
+    let solid_texel = (ATLAS_SIZE - 2, 0);
+    for y in 0..ATLAS_SIZE {
+        image.put_pixel(solid_texel.0, y, Rgba([255, 255, 255, 255]));
     }
-    image.save("/tmp/font.png").unwrap();
-    FontAtlas {
+    let mut atlas = FontAtlas {
         img: image,
         face,
-        advances,
+        glyphs: HashMap::new(),
+        fallback: GlyphInfo::default(),
+        blank_texel: (ATLAS_SIZE - 1, 0),
+        solid_texel,
+        cursor: (0, 0, 0),
+        dirty_rows: Some(0..ATLAS_SIZE),
+    };
+    atlas.fallback = atlas.rasterize(None)?.unwrap_or_default();
+    for c in PRELOADED_CHARS.iter().cloned().flatten() {
+        atlas.load_glyph(c);
+    }
+    if let Err(e) = atlas.img.save("/tmp/font.png") {
+        log::warn!("Could not save font atlas: {}", e);
     }
+    Ok(atlas)
 }
 
 impl FontAtlas {
+    pub fn new() -> Result<Self> {
+        load_font_atlas()
+    }
     fn size(&self) -> Extent3d {
         Extent3d {
             width: self.img.width(),
@@ -137,31 +183,102 @@ impl FontAtlas {
             depth_or_array_layers: 1,
         }
     }
+    /// Packs the glyph for `c`, or .notdef for `None`, into the next free spot of the atlas.
+    /// Returns `None` once the atlas is full.
+    fn rasterize(&mut self, c: Option<char>) -> Result<Option<GlyphInfo>> {
+        let glyph = render_glyph(&self.face, c)?;
+        let bitmap = glyph.bitmap();
+        let width = bitmap.width().unsigned_abs() / 3;
+        let height = bitmap.rows() as u32;
+
+        // Glyphs are packed in rows with a gap of two pixels, clear of the reserved columns
+        let (mut x, mut y, mut row_height) = self.cursor;
+        if x + width + 2 > ATLAS_SIZE - 2 {
+            x = 0;
+            y += row_height + 2;
+            row_height = 0;
+        }
+        if y + height > ATLAS_SIZE {
+            return Ok(None);
+        }
+        // Empty bitmaps, e.g. of a space, have no buffer
+        if width > 0 && height > 0 {
+            let bitmap_buf = bitmap.buffer();
+            for row in 0..height {
+                let start = (row * bitmap.pitch() as u32) as usize;
+                for (col, p) in bitmap_buf[start..start + width as usize * 3].chunks(3).enumerate() {
+                    self.img.put_pixel(x + col as u32, y + row, Rgba([p[0], p[1], p[2], 255]));
+                }
+            }
+        }
+        let dirty = self.dirty_rows.get_or_insert(y..y);
+        *dirty = dirty.start.min(y)..dirty.end.max(y + height);
+        self.cursor = (x + width + 2, y, row_height.max(height));
+        Ok(Some(GlyphInfo::from_metrics(&glyph.metrics(), (x as i32, y as i32), (width as i32, height as i32))))
+    }
+    /// Adds `c` to the atlas unless it is already there. Characters missing from the font, or
+    /// arriving once the atlas is full, are drawn with the .notdef glyph.
+    fn load_glyph(&mut self, c: char) {
+        if self.glyphs.contains_key(&c) {
+            return;
+        }
+        let info = if self.face.get_char_index(c as usize) == 0 {
+            None
+        } else {
+            self.rasterize(Some(c)).unwrap_or_else(|e| {
+                log::warn!("Failed to render {:?}: {}", c, e);
+                None
+            })
+        };
+        self.glyphs.insert(c, info.unwrap_or_else(|| self.fallback.clone()));
+    }
+    /// Adds the glyph of every cluster in `text` and the marks drawn over it.
+    fn load_glyphs(&mut self, text: &str) {
+        for cluster in text.graphemes(true) {
+            self.load_glyph(base_char(cluster));
+            for mark in cluster.chars().skip(1).filter(|&c| is_combining_mark(c)) {
+                self.load_glyph(mark);
+            }
+        }
+    }
     pub fn font_height(&self) -> u32 {
         self.face.size_metrics().unwrap().height as u32
     }
+    /// Width in pixels of a cell when laying text out on a character grid.
+    pub fn cell_width(&self) -> u32 {
+        (self.glyph('M').advance / 64) as u32
+    }
+    fn glyph(&self, c: char) -> &GlyphInfo {
+        self.glyphs.get(&c).unwrap_or(&self.fallback)
+    }
+    /// Ascender and descender of a line relative to its baseline, in 26.6 units.
+    fn line_extent(&self) -> (i32, i32) {
+        let metrics = self.face.size_metrics().unwrap();
+        (metrics.ascender as i32, metrics.descender as i32)
+    }
+    fn texel_texture(texel: (u32, u32)) -> RectanglePoint<f32> {
+        let (x, y) = (texel.0 as f32 + 0.5, texel.1 as f32 + 0.5);
+        RectanglePoint { x, y, x1: x, y1: y }
+    }
+    fn blank_texture(&self) -> RectanglePoint<f32> {
+        Self::texel_texture(self.blank_texel)
+    }
+    fn solid_texture(&self) -> RectanglePoint<f32> {
+        Self::texel_texture(self.solid_texel)
+    }
 }
 
 
-#[allow(unused)]
-fn random_str(len: usize) -> String {
-    let mut vec: Vec<u8> = Vec::with_capacity(len);
-    let slice = unsafe {
-        std::slice::from_raw_parts_mut(vec.as_mut_ptr(), len)
-    };
-    RANDFILE.lock().unwrap().read_exact(slice).unwrap();
-    unsafe { vec.set_len(len) };
-    String::from_utf8(vec).unwrap()
-}
-
+/// Key of an object in a `TextPass`. Lookups return `None` once the object was removed with
+/// `TextPass::remove_text`, and the editing helpers do nothing.
 pub struct TextObjectHandle(DefaultKey);
 
 impl TextObjectHandle {
-    pub fn resolve<'a>(&self, tp: &'a TextPass) -> &'a TextObject {
+    pub fn resolve<'a>(&self, tp: &'a TextPass) -> Option<&'a TextObject> {
         tp.query(self.0)
     }
 
-    pub fn resolve_mut<'a>(&self, tp: &'a mut TextPass) -> &'a mut TextObject {
+    pub fn resolve_mut<'a>(&self, tp: &'a mut TextPass) -> Option<&'a mut TextObject> {
         tp.query_mut(self.0)
     }
 
@@ -169,81 +286,205 @@ impl TextObjectHandle {
         tp.text_info.get(&self.0)
     }
     pub fn update_str(&self, tp: &mut TextPass, s: String) {
-        self.resolve_mut(tp).update_str(s);
+        if let Some(to) = self.resolve_mut(tp) {
+            to.update_str(s);
+        }
     }
     pub fn append_str(&self, tp: &mut TextPass, s: &str) {
-        let to = self.resolve_mut(tp);
-        to.render_str.push_str(s);
+        if let Some(to) = self.resolve_mut(tp) {
+            to.append_str(s);
+        }
+    }
+    pub fn append_styled(&self, tp: &mut TextPass, s: &str, style: TextStyle) {
+        if let Some(to) = self.resolve_mut(tp) {
+            to.append_styled(s, style);
+        }
+    }
+    pub fn add_span(&self, tp: &mut TextPass, range: Range<usize>, style: TextStyle) {
+        if let Some(to) = self.resolve_mut(tp) {
+            to.add_span(range, style);
+        }
+    }
+    pub fn clear_spans(&self, tp: &mut TextPass) {
+        if let Some(to) = self.resolve_mut(tp) {
+            to.clear_spans();
+        }
+    }
+    pub fn hit_test(&self, tp: &TextPass, point: (i32, i32)) -> Option<usize> {
+        tp.hit_test(self.0, point)
+    }
+    pub fn trim_front(&self, tp: &mut TextPass, n: usize) {
+        if n == 0 {
+            return;
+        }
+        if let Some(to) = self.resolve_mut(tp) {
+            to.trim_front(n);
+        }
+    }
+    pub fn scroll_by(&self, tp: &mut TextPass, dy: i32) {
+        if let Some(to) = self.resolve_mut(tp) {
+            to.scroll_by(dy);
+        }
     }
     pub fn add_offset(&self, tp: &mut TextPass, offset: (i32, i32)) {
-        let to = self.resolve_mut(tp);
-        to.top_left = (to.top_left.0 + offset.0, to.top_left.1 + offset.1);
+        if let Some(to) = self.resolve_mut(tp) {
+            to.top_left = (to.top_left.0 + offset.0, to.top_left.1 + offset.1);
+        }
     }
 }
 
 
 impl TextPass {
-    pub(crate) fn new(queue: &wgpu::Queue) -> Self {
-        let fontatl = load_font_atlas();
+    pub(crate) fn new(gpu: &Arc<GpuContext>, format: TextureFormat) -> Result<Self> {
+        let fontatl = FontAtlas::new()?;
         let atl_size = fontatl.size();
 
-        let verts = FontDrawRects::new();
-        let basic_state = BasicRenderState::new("font", 160, atl_size, verts.layout.clone(), BlendState::ALPHA_BLENDING);
-        queue.write_texture(ImageCopyTexture {
-            texture: &basic_state.texture,
-            mip_level: 0,
-            origin: Default::default(),
-            aspect: Default::default(),
-        }, fontatl.img.as_raw(), ImageDataLayout {
-            offset: 0,
-            bytes_per_row: Some(NonZeroU32::try_from(fontatl.img.width() * 4).unwrap()),
-            rows_per_image: Some(NonZeroU32::try_from(fontatl.img.height()).unwrap()),
-        }, atl_size);
+        let verts = GlyphInstances::new(gpu);
+        let basic_state = BasicRenderState::new(gpu, "font", atl_size, verts.layout.clone(), BlendState::ALPHA_BLENDING, format);
 
-        Self {
+        Ok(Self {
             state: basic_state,
             verts,
+            batches: Vec::new(),
             fontatl,
             time: 1.0,
             text_objects: Default::default(),
             text_info: Default::default(),
+            cache: Default::default(),
             dirty: true,
-        }
+        })
     }
-    fn draw_text(fontatl: &FontAtlas, verts: &mut FontDrawRects, TextObject { render_str, top_left, max_width, dirty: _ }: &TextObject) -> TextInfo {
-        let top_left = (top_left.0 * 64, top_left.1 * 64);
-        let max_width = max_width * 64;
+    const ITALIC_SKEW: f32 = 0.2;
+
+    /// Converts `rect_pos` from 26.6 to pixels. `skew` shifts the top edge of the quad right by
+    /// that fraction of its height.
+    fn push_quad(quads: &mut Vec<GlyphInstance>, fontatl: &FontAtlas, rect_pos: RectanglePoint, tex_pos: RectanglePoint<f32>, style: &TextStyle, skew: f32) {
         let atl_size = fontatl.size();
-        let mut cursor_origin = (top_left.0, top_left.1 - fontatl.font_height() as i32);
-        let iter = render_str.chars();
+        let shift = (rect_pos.y - rect_pos.y1) as f32 * skew / 64.0;
+        let rect = rect_pos.div_by_float(64.0, 64.0).as_array();
+        let tex_rect = tex_pos.div_by_float(atl_size.width as f64, atl_size.height as f64).as_array();
+        quads.push(GlyphInstance::new(rect, tex_rect, style.color, style.background, shift));
+    }
 
-        for c in iter {
-            if (cursor_origin.0 - top_left.0) as u32 > max_width || c == 0xd as char {
-                cursor_origin.0 = top_left.0;
-                cursor_origin.1 -= fontatl.font_height() as i32;
+    /// Walks the extended grapheme clusters of `range` in `to`, wrapping lines, and reports
+    /// where each one starts. `pen` is the baseline origin of the first cluster. Returns the pen
+    /// position after the last cluster.
+    fn layout_clusters<'a>(fontatl: &FontAtlas, to: &'a TextObject, range: Range<usize>, mut pen: (i32, i32), mut f: impl FnMut(PlacedCluster<'a>)) -> (i32, i32) {
+        let left = to.top_left.0 * 64;
+        let max_width = to.max_width * 64;
+        let line_height = fontatl.font_height() as i32;
+
+        let start = range.start;
+        for (idx, text) in to.render_str[range].grapheme_indices(true) {
+            let idx = idx + start;
+            if is_line_break(text) {
+                f(PlacedCluster { idx, text, origin: pen, advance: 0 });
+                pen = (left, pen.1 - line_height);
+                continue;
+            }
+            let base = base_char(text);
+            let advance = if base.is_control() {
+                0
+            } else {
+                to.cell_width.map_or(fontatl.glyph(base).advance, |w| w as i32 * 64)
+            };
+            // Wrap when the cluster would end past the edge, unless it is too wide for any line
+            if pen.0 > left && (pen.0 - left + advance) as u32 > max_width {
+                pen = (left, pen.1 - line_height);
             }
-            if let Some(gl_info) = &fontatl.advances.get(c as u8 as usize) {
-                let rect_pos = gl_info.calculate_rect_pos(cursor_origin);
-                let tex_pos = gl_info.calculate_texture();
+            f(PlacedCluster { idx, text, origin: pen, advance });
+            pen.0 += advance;
+        }
+        pen
+    }
 
+    /// Lays out only the lines of `to` in and around its viewport. Returns the rows that were
+    /// laid out along with the text's extent.
+    fn draw_text(fontatl: &FontAtlas, quads: &mut Vec<GlyphInstance>, to: &TextObject, lines: &LineIndex, spans: &SpanIndex) -> (TextInfo, Range<i32>) {
+        let (ascender, descender) = fontatl.line_extent();
+        let mut caret_pos = None;
+        let (range, pen, rows) = lines.visible(fontatl, to, LineIndex::overscan(fontatl, to));
+        let mut styles = SpanCursor::new(&to.spans, spans.first_reaching(range.start));
 
-                let [rectx, recty, rectx1, recty1] = rect_pos.div_by_float(WIDTH as f64 * 64.0, HEIGHT as f64 * 64.0).as_array();
-                let [textx, texty, textx1, texty1] = tex_pos.div_by_float(atl_size.width as f64, atl_size.height as f64).as_array();
+        Self::layout_clusters(fontatl, to, range, pen, |PlacedCluster { idx, text, origin, advance }| {
+            if to.caret == Some(idx) {
+                caret_pos = Some(origin);
+            }
+            let base = base_char(text);
+            if advance == 0 && base.is_control() {
+                return;
+            }
+            let style = styles.style_at(idx);
+            let gl_info = fontatl.glyph(base);
+            // Glyphs are centred in their cell when the text is laid out on a fixed grid
+            let pen = (origin.0 + (advance - gl_info.advance) / 2, origin.1);
+            if style.background[3] != 0 {
+                let cell = RectanglePoint {
+                    x: origin.0,
+                    y: origin.1 + ascender,
+                    x1: origin.0 + advance,
+                    y1: origin.1 + descender,
+                };
+                Self::push_quad(quads, fontatl, cell, fontatl.blank_texture(), &style, 0.0);
+            }
+            let skew = if style.italic { Self::ITALIC_SKEW } else { 0.0 };
+            let glyph_pos = gl_info.calculate_rect_pos(pen);
+            Self::push_quad(quads, fontatl, glyph_pos, gl_info.calculate_texture(), &style, skew);
+            if style.bold {
+                // Synthetic bold: overdraw the glyph one pixel to the right
+                let bold_pos = gl_info.calculate_rect_pos((pen.0 + 64, pen.1));
+                Self::push_quad(quads, fontatl, bold_pos, gl_info.calculate_texture(), &style, skew);
+            }
+            for mark in text.chars().skip(1).filter(|&c| is_combining_mark(c)) {
+                let mark_info = fontatl.glyph(mark);
+                let mark_pos = mark_info.calculate_mark_rect_pos(origin, advance);
+                Self::push_quad(quads, fontatl, mark_pos, mark_info.calculate_texture(), &style, skew);
+            }
+            if style.underline {
+                let underline = RectanglePoint {
+                    x: origin.0,
+                    y: origin.1 - 64,
+                    x1: origin.0 + advance,
+                    y1: origin.1 - 128,
+                };
+                Self::push_quad(quads, fontatl, underline, fontatl.solid_texture(), &style, 0.0);
+            }
+        });
+        let end = lines.end_pen(fontatl, to);
+        if to.caret == Some(to.render_str.len()) {
+            caret_pos = Some(end);
+        }
+        let info = TextInfo {
+            min: (to.top_left.0 * 64, to.top_left.1 * 64),
+            max: end,
+            caret: caret_pos,
+        };
+        (info, rows)
+    }
 
-                verts.extend([
-                    FontTriangleVertex::new((rectx, recty), (textx, texty)),
-                    FontTriangleVertex::new((rectx1, recty), (textx1, texty)),
-                    FontTriangleVertex::new((rectx, recty1), (textx, texty1)),
-                    FontTriangleVertex::new((rectx1, recty1), (textx1, texty1)),
-                ]);
+    /// Byte offset of the grapheme boundary nearest to `point`, given in the same pixel
+    /// coordinates as `TextObject::top_left`.
+    pub fn hit_test(&self, id: DefaultKey, point: (i32, i32)) -> Option<usize> {
+        let to = self.text_objects.get(id)?;
+        Self::hit_test_lines(&self.fontatl, to, &self.cache.get(&id)?.lines, point)
+    }
 
-                cursor_origin = gl_info.calculate_next_origin(cursor_origin);
+    fn hit_test_lines(fontatl: &FontAtlas, to: &TextObject, lines: &LineIndex, point: (i32, i32)) -> Option<usize> {
+        let (range, pen, _) = lines.visible(fontatl, to, LineIndex::MARGIN_ROWS);
+        let (ascender, descender) = fontatl.line_extent();
+        let point = (point.0 * 64, point.1 * 64);
+        let mut hit = None;
+        Self::layout_clusters(fontatl, to, range, pen, |PlacedCluster { idx, text, origin, advance }| {
+            if point.1 >= origin.1 + ascender || point.1 < origin.1 + descender || point.0 < origin.0 && hit.is_some() {
+                return;
             }
-        }
-        TextInfo {
-            min: top_left,
-            max: cursor_origin,
-        }
+            hit = Some(if is_line_break(text) || point.0 < origin.0 + advance / 2 {
+                idx
+            } else {
+                idx + text.len()
+            });
+        });
+        hit
     }
 
     pub(crate) fn add_text(&mut self, to: TextObject) -> TextObjectHandle {
@@ -251,45 +492,460 @@ impl TextPass {
         TextObjectHandle(self.text_objects.insert(to))
     }
 
+    /// Removes the object and its cached layout. Returns `None` if it was already removed.
+    pub fn remove_text(&mut self, handle: TextObjectHandle) -> Option<TextObject> {
+        let to = self.text_objects.remove(handle.0)?;
+        self.cache.remove(&handle.0);
+        self.text_info.remove(&handle.0);
+        self.dirty = true;
+        Some(to)
+    }
+
+    /// Brings the cached layout of `to` up to date, re-measuring only the lines from the first
+    /// change on. The quads are only rebuilt when the text or its width changed, or when the
+    /// viewport left the rows laid out last time; moving and scrolling are left to
+    /// `ObjectCache::translate`.
+    fn layout_object(fontatl: &mut FontAtlas, to: &mut TextObject, cache: &mut ObjectCache) -> TextInfo {
+        let line_height = fontatl.font_height() as i32 / 64;
+        let ObjectCache { quads, lines, spans, drawn } = cache;
+        let content_changed = to.changed_from != usize::MAX || to.spans_changed_from != usize::MAX || to.trimmed_front != 0;
+        if to.changed_from <= to.render_str.len() {
+            // From the start of the line, since a cluster may span the edit
+            let line_start = to.render_str[..to.changed_from].rfind('\n').map_or(0, |i| i + 1);
+            fontatl.load_glyphs(&to.render_str[line_start..]);
+        }
+        // Keep the viewport on the same text when lines above it are dropped
+        let trimmed_rows = lines.trim_front(to.trimmed_front);
+        to.scroll -= trimmed_rows as i32 * line_height;
+        lines.update(fontatl, to);
+        spans.update(&to.spans, to.spans_changed_from);
+        to.changed_from = usize::MAX;
+        to.spans_changed_from = usize::MAX;
+        to.trimmed_front = 0;
+
+        if let Some(view_height) = to.view_height {
+            let max_scroll = (lines.total_rows as i32 * line_height - view_height as i32).max(0);
+            // Scrolling back to the bottom resumes following new output
+            to.follow_tail |= to.scroll >= max_scroll;
+            to.scroll = if to.follow_tail { max_scroll } else { to.scroll.clamp(0, max_scroll) };
+        }
+
+        to.dirty = false;
+        let params = (to.max_width, to.cell_width, to.caret);
+        let needed = LineIndex::rows_in_view(fontatl, to, 0);
+        let reusable = drawn.as_ref().filter(|d| {
+            !content_changed && d.params == params && d.rows.start <= needed.start && needed.end <= d.rows.end
+        });
+        if reusable.is_none() {
+            quads.clear();
+            let (info, rows) = Self::draw_text(fontatl, quads, to, lines, spans);
+            *drawn = Some(DrawnLayout { origin: DrawnLayout::origin(to), params, rows, info });
+        }
+        cache.translated_info(to)
+    }
+
+    /// Copies the rows of the atlas that new glyphs were added to into the texture.
+    fn upload_glyphs(&mut self) {
+        let rows = match self.fontatl.dirty_rows.take() {
+            Some(rows) if !rows.is_empty() => rows,
+            _ => return,
+        };
+        let row_bytes = self.fontatl.img.width() * 4;
+        self.state.gpu.queue.write_texture(ImageCopyTexture {
+            texture: &self.state.texture,
+            mip_level: 0,
+            origin: Origin3d { x: 0, y: rows.start, z: 0 },
+            aspect: Default::default(),
+        }, &self.fontatl.img.as_raw()[(rows.start * row_bytes) as usize..(rows.end * row_bytes) as usize], ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(NonZeroU32::try_from(row_bytes).unwrap()),
+            rows_per_image: Some(NonZeroU32::try_from(rows.len() as u32).unwrap()),
+        }, Extent3d {
+            width: self.fontatl.img.width(),
+            height: rows.len() as u32,
+            depth_or_array_layers: 1,
+        });
+    }
+
+    /// Lays out objects that changed since the last update and rebuilds the vertex list from
+    /// the cached geometry of every object.
     pub fn update(&mut self) {
-        for (key, to) in &self.text_objects {
-            let stats = Self::draw_text(&self.fontatl, &mut self.verts, to);
+        if !self.dirty {
+            return;
+        }
+        for (key, to) in &mut self.text_objects {
+            if !to.dirty && self.cache.contains_key(&key) {
+                continue;
+            }
+            let stats = Self::layout_object(&mut self.fontatl, to, self.cache.entry(key).or_default());
             self.text_info.insert(key, stats);
         }
+        self.upload_glyphs();
+
+        let mut quads = 0;
+        self.batches.clear();
+        for (key, to) in &self.text_objects {
+            let cache = &self.cache[&key];
+            self.verts.set_instances(quads, &cache.quads);
+            Batch::push_translated(&mut self.batches, quads..quads + cache.quads.len(), to.clip, cache.translate(to));
+            quads += cache.quads.len();
+        }
+        self.verts.truncate(quads);
         self.dirty = false;
     }
 
-    pub(crate) fn render_self<'a>(&'a mut self, p: &mut RenderPass<'a>, queue: &mut wgpu::Queue, translate: (f32, f32)) {
-        assert!(!self.dirty);
+    pub fn query(&self, id: DefaultKey) -> Option<&TextObject> {
+        self.text_objects.get(id)
+    }
+    /// Marks the object for re-layout, since any field may be changed through the reference.
+    pub fn query_mut(&mut self, id: DefaultKey) -> Option<&mut TextObject> {
+        let to = self.text_objects.get_mut(id)?;
+        to.dirty = true;
+        self.dirty = true;
+        Some(to)
+    }
+}
 
-        self.verts.confirm_extends(queue);
+impl InstancedPass for TextPass {
+    type Instance = GlyphInstance;
 
-        queue.write_buffer(&self.state.uniform_buffer, 0, bytemuck::cast_slice(&[translate.0, translate.1]));
-        p.set_pipeline(&self.state.render_pipeline);
-        p.set_vertex_buffer(0, self.verts.get_vertex_buf());
-        p.set_index_buffer(self.verts.get_index_buffer(), IndexFormat::Uint32);
-        p.set_bind_group(0, &self.state.bind_group, &[]);
-        p.draw_indexed(0..(self.verts.index_buffer.len()) as u32, 0, 0..1);
+    /// Lays out text that changed since `update` was last called. Objects that only moved or
+    /// scrolled keep their glyphs and get a new batch translation.
+    fn upload_data(&mut self) {
+        self.update();
     }
 
-    pub fn query(&self, id: DefaultKey) -> &TextObject {
-        self.text_objects.get(id).unwrap()
+    fn parts(&self) -> (&BasicRenderState, &GlyphInstances, &[Batch]) {
+        (&self.state, &self.verts, &self.batches)
     }
-    pub fn query_mut(&mut self, id: DefaultKey) -> &mut TextObject {
-        self.dirty = true;
-        self.text_objects.get_mut(id).unwrap()
+
+    fn parts_mut(&mut self) -> (&mut BasicRenderState, &mut GlyphInstances, &[Batch]) {
+        (&mut self.state, &mut self.verts, &self.batches)
     }
+}
 
-    pub(crate) fn finish(&mut self) {
-        self.verts.finish();
+
+/// Layout state of one text object, reused until the object is dirtied.
+#[derive(Default)]
+struct ObjectCache {
+    /// Quads of the visible part of the object from its last layout.
+    quads: Vec<GlyphInstance>,
+    lines: LineIndex,
+    spans: SpanIndex,
+    drawn: Option<DrawnLayout>,
+}
+
+impl ObjectCache {
+    /// Pixels the quads must be moved by to match where `to` is now.
+    fn translate(&self, to: &TextObject) -> (f32, f32) {
+        let drawn = match &self.drawn {
+            Some(drawn) => drawn.origin,
+            None => return (0.0, 0.0),
+        };
+        let origin = DrawnLayout::origin(to);
+        ((origin.0 - drawn.0) as f32, (origin.1 - drawn.1) as f32)
+    }
+
+    /// The extent from the last layout, moved like the quads.
+    fn translated_info(&self, to: &TextObject) -> TextInfo {
+        let info = self.drawn.as_ref().unwrap().info.clone();
+        let (dx, dy) = self.translate(to);
+        let (dx, dy) = (dx as i32 * 64, dy as i32 * 64);
+        let shift = |p: (i32, i32)| (p.0 + dx, p.1 + dy);
+        TextInfo { min: shift(info.min), max: shift(info.max), caret: info.caret.map(shift) }
     }
 }
 
+/// What `ObjectCache::quads` were laid out for.
+struct DrawnLayout {
+    /// `top_left` moved up by the scroll position at the time.
+    origin: (i32, i32),
+    /// `max_width`, `cell_width` and `caret` at the time.
+    params: (u32, Option<u32>, Option<usize>),
+    /// Rows of the text covered by the quads.
+    rows: Range<i32>,
+    info: TextInfo,
+}
+
+impl DrawnLayout {
+    fn origin(to: &TextObject) -> (i32, i32) {
+        (to.top_left.0, to.top_left.1 + to.scroll)
+    }
+}
+
+/// Start of every `\n`-separated line of a text object and the number of wrapped rows above
+/// it, so the lines inside a viewport can be found without laying out the whole text.
+#[derive(Default)]
+struct LineIndex {
+    starts: Vec<usize>,
+    rows_before: Vec<u32>,
+    total_rows: u32,
+    /// `max_width` and `cell_width` the rows were measured with.
+    measured_for: (u32, Option<u32>),
+}
+
+impl LineIndex {
+    /// Lines kept laid out above and below the viewport.
+    const MARGIN_ROWS: i32 = 2;
+
+    /// Drops the lines in the first `n` bytes, which were trimmed from the text, and returns
+    /// how many rows they took up.
+    fn trim_front(&mut self, n: usize) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        let removed = self.starts.partition_point(|&s| s < n);
+        if self.starts.get(removed) != Some(&n) {
+            // Not trimmed at a line start; measure everything again
+            let rows = self.total_rows;
+            *self = Default::default();
+            return rows;
+        }
+        let removed_rows = self.rows_before[removed];
+        self.starts.drain(..removed);
+        self.rows_before.drain(..removed);
+        for start in &mut self.starts {
+            *start -= n;
+        }
+        for rows in &mut self.rows_before {
+            *rows -= removed_rows;
+        }
+        self.total_rows -= removed_rows;
+        removed_rows
+    }
+
+    /// Re-measures the lines from the first one touched since the last update.
+    fn update(&mut self, fontatl: &FontAtlas, to: &TextObject) {
+        let params = (to.max_width, to.cell_width);
+        let from = if params == self.measured_for { to.changed_from } else { 0 };
+        if from == usize::MAX && !self.starts.is_empty() {
+            return;
+        }
+        let line = self.starts.partition_point(|&s| s <= from).saturating_sub(1);
+        let mut start = self.starts.get(line).copied().unwrap_or(0);
+        let mut rows = self.rows_before.get(line).copied().unwrap_or(0);
+        self.starts.truncate(line);
+        self.rows_before.truncate(line);
+
+        let text = &to.render_str;
+        loop {
+            let end = text[start..].find('\n').map_or(text.len(), |i| start + i + 1);
+            self.starts.push(start);
+            self.rows_before.push(rows);
+            rows += Self::measure_rows(fontatl, to, start..end);
+            if end == text.len() {
+                break;
+            }
+            start = end;
+        }
+        if text.ends_with('\n') {
+            self.starts.push(text.len());
+            self.rows_before.push(rows);
+            rows += 1;
+        }
+        self.total_rows = rows;
+        self.measured_for = params;
+    }
+
+    /// Number of rows `range` wraps to, not counting a trailing line break.
+    fn measure_rows(fontatl: &FontAtlas, to: &TextObject, range: Range<usize>) -> u32 {
+        let line_height = fontatl.font_height() as i32;
+        let trailing_break = to.render_str[range.clone()].ends_with('\n');
+        let end = TextPass::layout_clusters(fontatl, to, range, (to.top_left.0 * 64, 0), |_| {});
+        (-end.1 / line_height) as u32 + if trailing_break { 0 } else { 1 }
+    }
+
+    /// Pen origin of the first row of `line`, taking the object's scroll position into account.
+    fn line_pen(&self, fontatl: &FontAtlas, to: &TextObject, line: usize) -> (i32, i32) {
+        let line_height = fontatl.font_height() as i32;
+        let top = (to.top_left.1 + to.scroll) * 64;
+        (to.top_left.0 * 64, top - (self.rows_before[line] as i32 + 1) * line_height)
+    }
+
+    /// Rows intersecting the viewport, widened by `margin` rows on either side.
+    fn rows_in_view(fontatl: &FontAtlas, to: &TextObject, margin: i32) -> Range<i32> {
+        let line_height = fontatl.font_height() as i32;
+        match to.view_height {
+            Some(height) => to.scroll * 64 / line_height - margin..(to.scroll + height as i32) * 64 / line_height + 1 + margin,
+            None => 0..i32::MAX,
+        }
+    }
+
+    /// Byte range of the lines intersecting the viewport widened by `margin` rows, the pen
+    /// origin it starts at, and the rows those lines cover.
+    fn visible(&self, fontatl: &FontAtlas, to: &TextObject, margin: i32) -> (Range<usize>, (i32, i32), Range<i32>) {
+        let rows = Self::rows_in_view(fontatl, to, margin);
+        let first = self.rows_before.partition_point(|&r| r as i32 <= rows.start).saturating_sub(1);
+        let last = self.rows_before.partition_point(|&r| (r as i32) < rows.end);
+        let start = self.starts[first];
+        let end = self.starts.get(last).copied().unwrap_or(to.render_str.len());
+        // Lines running to the end of the text cover any row after it as well
+        let covered = self.rows_before[first] as i32..self.rows_before.get(last).map_or(i32::MAX, |&r| r as i32);
+        (start..end, self.line_pen(fontatl, to, first), covered)
+    }
+
+    /// Rows laid out beyond the viewport, so scrolling up to a viewport's height either way
+    /// only moves the existing quads.
+    fn overscan(fontatl: &FontAtlas, to: &TextObject) -> i32 {
+        to.view_height.map_or(0, |h| h as i32 * 64 / fontatl.font_height() as i32) + Self::MARGIN_ROWS
+    }
+
+    /// Pen position after the last character, found by laying out only the last line.
+    fn end_pen(&self, fontatl: &FontAtlas, to: &TextObject) -> (i32, i32) {
+        let last = self.starts.len() - 1;
+        let pen = self.line_pen(fontatl, to, last);
+        TextPass::layout_clusters(fontatl, to, self.starts[last]..to.render_str.len(), pen, |_| {})
+    }
+}
+
+/// Largest `range.end` of the spans up to each one. Spans are sorted by start, so the first
+/// span reaching into the viewport is found by binary search instead of walking them all.
+#[derive(Default)]
+struct SpanIndex {
+    max_ends: Vec<usize>,
+}
+
+impl SpanIndex {
+    /// Recomputes the entries from span `from`, the first one changed since the last update.
+    fn update(&mut self, spans: &[TextSpan], from: usize) {
+        self.max_ends.truncate(from.min(spans.len()));
+        let mut max_end = self.max_ends.last().copied().unwrap_or(0);
+        for span in &spans[self.max_ends.len()..] {
+            max_end = max_end.max(span.range.end);
+            self.max_ends.push(max_end);
+        }
+    }
+
+    /// Index of the first span that ends after `idx`; no span before it covers `idx` or
+    /// anything after it.
+    fn first_reaching(&self, idx: usize) -> usize {
+        self.max_ends.partition_point(|&end| end <= idx)
+    }
+}
+
+/// An extended grapheme cluster placed on a line by `TextPass::layout_clusters`.
+struct PlacedCluster<'a> {
+    idx: usize,
+    text: &'a str,
+    origin: (i32, i32),
+    advance: i32,
+}
+
+fn is_line_break(cluster: &str) -> bool {
+    matches!(cluster, "\n" | "\r" | "\r\n")
+}
+
+/// Marks drawn over the base glyph of their cluster instead of advancing the pen.
+fn is_combining_mark(c: char) -> bool {
+    matches!(c as u32, 0x300..=0x36f | 0x1ab0..=0x1aff | 0x1dc0..=0x1dff | 0x20d0..=0x20ff | 0xfe20..=0xfe2f)
+}
+
+/// Composes a conjoining Hangul jamo sequence (L V, or L V T) into its precomposed syllable.
+fn compose_hangul(cluster: &str) -> Option<char> {
+    let mut chars = cluster.chars().map(|c| c as u32);
+    let (l, v, t) = (chars.next()?, chars.next()?, chars.next());
+    if chars.next().is_some() || !(0x1100..=0x1112).contains(&l) || !(0x1161..=0x1175).contains(&v) {
+        return None;
+    }
+    let t = match t {
+        None => 0,
+        Some(t @ 0x11a8..=0x11c2) => t - 0x11a7,
+        Some(_) => return None,
+    };
+    char::from_u32(0xac00 + (l - 0x1100) * 588 + (v - 0x1161) * 28 + t)
+}
+
+/// The character whose glyph represents a cluster; any remaining characters are marks or
+/// joiners.
+fn base_char(cluster: &str) -> char {
+    compose_hangul(cluster).unwrap_or_else(|| cluster.chars().next().unwrap_or(' '))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextStyle {
+    pub color: [u8; 4],
+    pub background: [u8; 4],
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl TextStyle {
+    pub const DEFAULT: TextStyle = TextStyle {
+        color: [0, 0, 0, 255],
+        background: [255, 255, 255, 0],
+        bold: false,
+        italic: false,
+        underline: false,
+    };
+    pub fn with_color(color: [u8; 4]) -> Self {
+        Self { color, ..Self::DEFAULT }
+    }
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Styles the bytes of `range` in the owning `TextObject`'s `render_str`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextSpan {
+    pub range: Range<usize>,
+    pub style: TextStyle,
+}
+
+/// Walks spans sorted by start while the text is iterated in order, starting at span `first`.
+/// Where spans overlap, the one that started last wins.
+struct SpanCursor<'a> {
+    spans: &'a [TextSpan],
+    next: usize,
+    active: Vec<usize>,
+}
+
+impl<'a> SpanCursor<'a> {
+    fn new(spans: &'a [TextSpan], first: usize) -> Self {
+        Self { spans, next: first, active: Vec::new() }
+    }
+    fn style_at(&mut self, idx: usize) -> TextStyle {
+        while self.next < self.spans.len() && self.spans[self.next].range.start <= idx {
+            self.active.push(self.next);
+            self.next += 1;
+        }
+        let spans = self.spans;
+        self.active.retain(|&s| spans[s].range.end > idx);
+        self.active.last().map_or(TextStyle::DEFAULT, |&s| spans[s].style)
+    }
+}
 
 pub struct TextObject {
     pub render_str: String,
     pub top_left: (i32, i32),
     pub max_width: u32,
+    /// Sorted by start. Assigning them directly is only safe right after `update_str`;
+    /// otherwise use `add_span`.
+    pub spans: Vec<TextSpan>,
+    /// Byte offset in `render_str` the pane cursor is drawn at, instead of the end of the text.
+    pub caret: Option<usize>,
+    /// Fixed advance in pixels for every glyph, for text laid out on a character grid.
+    pub cell_width: Option<u32>,
+    /// Height in pixels of the visible region below `top_left`. Only the lines inside it are
+    /// laid out; `None` lays out the whole text.
+    pub view_height: Option<u32>,
+    /// Pixels scrolled from the top of the text to the top of the viewport.
+    pub scroll: i32,
+    /// Keeps the viewport at the end of the text as it grows.
+    pub follow_tail: bool,
+    /// Glyphs are cut off outside of this region.
+    pub clip: Option<ClipRect>,
+    /// Earliest byte of `render_str` changed since the last layout, or `usize::MAX`. Edits
+    /// should go through the methods on `TextObject` so the line index stays correct.
+    pub(crate) changed_from: usize,
+    /// Index of the first span changed since the last layout, or `usize::MAX`.
+    pub(crate) spans_changed_from: usize,
+    /// Bytes removed from the front of `render_str` since the last layout.
+    pub(crate) trimmed_front: usize,
     pub dirty: bool,
 }
 
@@ -299,11 +955,402 @@ impl TextObject {
             render_str: str.to_owned(),
             top_left: bl,
             max_width: width,
+            spans: Vec::new(),
+            caret: None,
+            cell_width: None,
+            view_height: None,
+            scroll: 0,
+            follow_tail: true,
+            clip: None,
+            changed_from: 0,
+            spans_changed_from: 0,
+            trimmed_front: 0,
             dirty: true,
         }
     }
+    /// The region the object is laid out in, if it has a fixed height.
+    pub fn viewport(&self) -> Option<ClipRect> {
+        let h = self.view_height?;
+        Some(ClipRect { x: self.top_left.0, y: self.top_left.1 - h as i32, w: self.max_width, h })
+    }
     pub(crate) fn update_str(&mut self, new: String) {
         self.render_str = new;
+        self.spans.clear();
+        self.changed_from = 0;
+        self.spans_changed_from = 0;
+        self.dirty = true;
+    }
+    pub fn append_str(&mut self, s: &str) {
+        self.changed_from = self.changed_from.min(self.render_str.len());
+        self.render_str.push_str(s);
+        self.dirty = true;
+    }
+    /// Removes the first `n` bytes, which should end at the start of a line. Spans and the
+    /// caret are shifted to stay on the same text.
+    pub fn trim_front(&mut self, n: usize) {
+        let n = n.min(self.render_str.len());
+        if n == 0 {
+            return;
+        }
+        self.render_str.drain(..n);
+        for span in &mut self.spans {
+            span.range = span.range.start.saturating_sub(n)..span.range.end.saturating_sub(n);
+        }
+        self.spans.retain(|span| !span.range.is_empty());
+        self.spans_changed_from = 0;
+        self.caret = self.caret.map(|c| c.saturating_sub(n));
+        if self.changed_from != usize::MAX {
+            self.changed_from = self.changed_from.saturating_sub(n);
+        }
+        self.trimmed_front += n;
+        self.dirty = true;
+    }
+    /// Moves the viewport down by `dy` pixels; scrolling up stops following the end of the text.
+    pub fn scroll_by(&mut self, dy: i32) {
+        self.scroll += dy;
+        if dy < 0 {
+            self.follow_tail = false;
+        }
+        self.dirty = true;
+    }
+    /// Spans are kept ordered by start; a span added later takes precedence over earlier
+    /// spans with the same start.
+    pub fn add_span(&mut self, range: Range<usize>, style: TextStyle) {
+        if range.is_empty() {
+            return;
+        }
+        let at = self.spans.partition_point(|s| s.range.start <= range.start);
+        self.spans.insert(at, TextSpan { range, style });
+        self.spans_changed_from = self.spans_changed_from.min(at);
         self.dirty = true;
     }
+    pub fn clear_spans(&mut self) {
+        self.spans.clear();
+        self.spans_changed_from = 0;
+        self.dirty = true;
+    }
+    /// Moves the caret one grapheme cluster towards the start of the text.
+    pub fn caret_left(&mut self) {
+        let len = self.render_str.len();
+        let mut cursor = GraphemeCursor::new(self.caret.unwrap_or(len).min(len), len, true);
+        if let Ok(Some(prev)) = cursor.prev_boundary(&self.render_str, 0) {
+            self.caret = Some(prev);
+            self.dirty = true;
+        }
+    }
+    /// Moves the caret one grapheme cluster towards the end of the text.
+    pub fn caret_right(&mut self) {
+        let len = self.render_str.len();
+        let mut cursor = GraphemeCursor::new(self.caret.unwrap_or(len).min(len), len, true);
+        if let Ok(Some(next)) = cursor.next_boundary(&self.render_str, 0) {
+            self.caret = Some(next);
+            self.dirty = true;
+        }
+    }
+    /// Appends `s` drawn with `style`, extending the last span when it has the same style.
+    pub fn append_styled(&mut self, s: &str, style: TextStyle) {
+        let start = self.render_str.len();
+        self.append_str(s);
+        if style == TextStyle::DEFAULT || s.is_empty() {
+            return;
+        }
+        match self.spans.last_mut() {
+            Some(last) if last.range.end == start && last.style == style => {
+                last.range.end = self.render_str.len();
+                self.spans_changed_from = self.spans_changed_from.min(self.spans.len() - 1);
+            }
+            _ => self.add_span(start..self.render_str.len(), style),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text on a grid of 10 pixel cells, four cells to a row.
+    fn grid_text(s: &str) -> TextObject {
+        TextObject { cell_width: Some(10), ..TextObject::new(s, (0, 0), 40) }
+    }
+
+    fn measure(fontatl: &FontAtlas, to: &TextObject) -> LineIndex {
+        let mut lines = LineIndex::default();
+        lines.update(fontatl, to);
+        lines
+    }
+
+    fn summary(lines: &LineIndex) -> (Vec<usize>, Vec<u32>, u32) {
+        (lines.starts.clone(), lines.rows_before.clone(), lines.total_rows)
+    }
+
+    fn line_height(fontatl: &FontAtlas) -> i32 {
+        fontatl.font_height() as i32 / 64
+    }
+
+    /// Ten one-row lines in a viewport three rows high.
+    fn scrolling_text(fontatl: &mut FontAtlas) -> (TextObject, ObjectCache) {
+        let text: Vec<_> = (0..10).map(|i| i.to_string()).collect();
+        let view_height = 3 * line_height(fontatl) as u32;
+        let mut to = TextObject { view_height: Some(view_height), ..grid_text(&text.join("\n")) };
+        let mut cache = ObjectCache::default();
+        TextPass::layout_object(fontatl, &mut to, &mut cache);
+        (to, cache)
+    }
+
+    #[test]
+    fn line_index() {
+        let fontatl = FontAtlas::new().unwrap();
+        let lines = measure(&fontatl, &grid_text("ab\nabcdefghi\n\nx"));
+        assert_eq!(summary(&lines), (vec![0, 3, 13, 14], vec![0, 1, 4, 5], 6));
+        let lines = measure(&fontatl, &grid_text("ab\n"));
+        assert_eq!(summary(&lines), (vec![0, 3], vec![0, 1], 2));
+    }
+
+    #[test]
+    fn line_index_follows_appends() {
+        let fontatl = FontAtlas::new().unwrap();
+        let mut to = grid_text("ab\nabc");
+        let mut lines = measure(&fontatl, &to);
+        to.changed_from = usize::MAX;
+        to.append_str("defgh\nij\n");
+        lines.update(&fontatl, &to);
+        assert_eq!(summary(&lines), summary(&measure(&fontatl, &to)));
+    }
+
+    #[test]
+    fn line_index_trim_front() {
+        let fontatl = FontAtlas::new().unwrap();
+        let mut to = grid_text("abcdefghi\nab\ncd\n");
+        let mut lines = measure(&fontatl, &to);
+        // Trimmed at a line start, the rows of the dropped line are returned
+        to.trim_front(10);
+        assert_eq!(lines.trim_front(10), 3);
+        assert_eq!(summary(&lines), summary(&measure(&fontatl, &to)));
+        // Anywhere else the index starts over
+        assert_eq!(lines.trim_front(1), 3);
+        assert!(lines.starts.is_empty());
+    }
+
+    #[test]
+    fn follow_tail() {
+        let mut fontatl = FontAtlas::new().unwrap();
+        let line_height = line_height(&fontatl);
+        let (mut to, mut cache) = scrolling_text(&mut fontatl);
+        assert_eq!(to.scroll, 7 * line_height);
+
+        to.append_str("\nx");
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert_eq!(to.scroll, 8 * line_height);
+
+        // Scrolling up stops following new output
+        to.scroll_by(-2 * line_height);
+        to.append_str("\ny");
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert_eq!(to.scroll, 6 * line_height);
+        assert!(!to.follow_tail);
+
+        // Scrolling is clamped to the text, and reaching the bottom follows again
+        to.scroll_by(-100 * line_height);
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert_eq!(to.scroll, 0);
+        to.scroll_by(100 * line_height);
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert_eq!(to.scroll, 9 * line_height);
+        assert!(to.follow_tail);
+    }
+
+    #[test]
+    fn scrolling_reuses_quads() {
+        let mut fontatl = FontAtlas::new().unwrap();
+        let line_height = line_height(&fontatl);
+        let (mut to, mut cache) = scrolling_text(&mut fontatl);
+        let info = TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        let quads = cache.quads.clone();
+
+        // Within the rows laid out around the viewport only the translation changes
+        to.scroll_by(-2 * line_height);
+        let scrolled = TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert!(cache.quads == quads);
+        assert_eq!(cache.translate(&to), (0.0, -2.0 * line_height as f32));
+        assert_eq!(scrolled.max.1, info.max.1 - 2 * line_height * 64);
+
+        // So does moving the object
+        to.top_left.0 += 7;
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert!(cache.quads == quads);
+        assert_eq!(cache.translate(&to), (7.0, -2.0 * line_height as f32));
+
+        // Past those rows the text is laid out again where it is now
+        to.scroll_by(-5 * line_height);
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert!(cache.quads != quads);
+        assert_eq!(cache.translate(&to), (0.0, 0.0));
+
+        // As it is when the text changes
+        to.scroll_by(line_height);
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert_ne!(cache.translate(&to), (0.0, 0.0));
+        to.append_str("\nx");
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert_eq!(cache.translate(&to), (0.0, 0.0));
+    }
+
+    #[test]
+    fn trim_front_keeps_viewport() {
+        let mut fontatl = FontAtlas::new().unwrap();
+        let line_height = line_height(&fontatl);
+        let (mut to, mut cache) = scrolling_text(&mut fontatl);
+        to.scroll_by(-3 * line_height);
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        let (range, _, _) = cache.lines.visible(&fontatl, &to, 0);
+        let visible = to.render_str[range].to_owned();
+
+        to.trim_front(4);
+        TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+        assert_eq!(to.scroll, 2 * line_height);
+        let (range, _, _) = cache.lines.visible(&fontatl, &to, 0);
+        assert_eq!(to.render_str[range], visible);
+        assert_eq!(summary(&cache.lines), summary(&measure(&fontatl, &to)));
+    }
+
+    #[test]
+    fn span_cursor_seeks() {
+        let bold = TextStyle { bold: true, ..TextStyle::DEFAULT };
+        let red = TextStyle::with_color([255, 0, 0, 255]);
+        let mut to = TextObject::new(&"x".repeat(100), (0, 0), 1000);
+        to.add_span(0..50, bold);
+        to.add_span(10..20, red);
+        to.add_span(60..70, red);
+        let mut index = SpanIndex::default();
+        index.update(&to.spans, to.spans_changed_from);
+        assert_eq!([5, 55, 80].map(|idx| index.first_reaching(idx)), [0, 2, 3]);
+
+        let styles = |from: usize| {
+            let mut cursor = SpanCursor::new(&to.spans, index.first_reaching(from));
+            (from..100).map(|idx| cursor.style_at(idx)).collect::<Vec<_>>()
+        };
+        let all = styles(0);
+        assert_eq!((all[5], all[15], all[25], all[55], all[65]), (bold, red, bold, TextStyle::DEFAULT, red));
+        for from in [15, 25, 55, 65, 80] {
+            assert_eq!(styles(from), all[from..], "from {}", from);
+        }
+
+        to.spans_changed_from = usize::MAX;
+        to.append_styled("yy", red);
+        index.update(&to.spans, to.spans_changed_from);
+        assert_eq!(index.max_ends, [50, 50, 70, 102]);
+    }
+
+    fn clusters(fontatl: &FontAtlas, to: &TextObject) -> Vec<(usize, (i32, i32))> {
+        let mut placed = Vec::new();
+        TextPass::layout_clusters(fontatl, to, 0..to.render_str.len(), (0, 0), |cluster| placed.push((cluster.idx, cluster.origin)));
+        placed
+    }
+
+    #[test]
+    fn cluster_layout() {
+        let fontatl = FontAtlas::new().unwrap();
+        // A mark on its base, a Hangul jamo sequence and a ZWJ emoji sequence each take one cell
+        let to = grid_text("e\u{301}\u{1112}\u{1161}\u{11ab}\u{1f469}\u{200d}\u{1f4bb}x");
+        assert_eq!(clusters(&fontatl, &to), [(0, (0, 0)), (3, (640, 0)), (12, (1280, 0)), (23, (1920, 0))]);
+        assert_eq!(base_char("e\u{301}"), 'e');
+        assert_eq!(base_char("\u{1112}\u{1161}\u{11ab}"), '\u{d55c}');
+        assert_eq!(base_char("\u{1f469}\u{200d}\u{1f4bb}"), '\u{1f469}');
+        assert_eq!(compose_hangul("\u{1112}\u{1161}"), Some('\u{d558}'));
+        assert_eq!(compose_hangul("\u{1112}x"), None);
+    }
+
+    #[test]
+    fn wrapping() {
+        let fontatl = FontAtlas::new().unwrap();
+        let height = fontatl.font_height() as i32;
+        let cells = |s: &str| -> Vec<_> {
+            clusters(&fontatl, &grid_text(s)).iter().map(|&(_, (x, y))| (x / 640, -y / height)).collect()
+        };
+        assert_eq!(cells("abcdef"), [(0, 0), (1, 0), (2, 0), (3, 0), (0, 1), (1, 1)]);
+        // Line breaks end their row and reset the pen
+        assert_eq!(cells("ab\ncd"), [(0, 0), (1, 0), (2, 0), (0, 1), (1, 1)]);
+
+        let to = TextObject::new("iiii WWWW iiii", (0, 0), 40);
+        let placed = clusters(&fontatl, &to);
+        assert!(placed.iter().all(|&(_, (x, _))| x <= 40 * 64));
+        let (_, (_, last_row)) = *placed.last().unwrap();
+        assert!(last_row < 0);
+    }
+
+    #[test]
+    fn wrapped_glyphs_fit_the_width() {
+        let mut fontatl = FontAtlas::new().unwrap();
+        let on_grey = TextStyle { background: [200, 200, 200, 255], ..TextStyle::DEFAULT };
+        // Proportional, and on a grid of cells as wide as the widest of these glyphs
+        for cell_width in [None, Some(fontatl.cell_width())] {
+            let mut to = TextObject { cell_width, ..TextObject::new("iiii MMMM nnnn iiii MMMM", (5, 0), 40) };
+            to.add_span(0..to.render_str.len(), on_grey);
+            let mut cache = ObjectCache::default();
+            TextPass::layout_object(&mut fontatl, &mut to, &mut cache);
+            assert!(!cache.quads.is_empty());
+            for quad in &cache.quads {
+                let [left, _, right, _] = quad.rect;
+                assert!(left >= 5.0 && right <= 45.0, "{:?} in {:?}", (left, right), cell_width);
+            }
+        }
+    }
+
+    #[test]
+    fn hit_test() {
+        let fontatl = FontAtlas::new().unwrap();
+        let line_height = line_height(&fontatl);
+        let to = grid_text("abcdef\ngh");
+        let lines = measure(&fontatl, &to);
+        // Two pixels above the baseline of `row`
+        let hit = |x: i32, row: i32| TextPass::hit_test_lines(&fontatl, &to, &lines, (x, -(row + 1) * line_height + 2));
+        assert_eq!(hit(2, 0), Some(0));
+        assert_eq!(hit(12, 0), Some(1));
+        assert_eq!(hit(17, 0), Some(2));
+        // Past the end of a wrapped row, and of a line before its line break
+        assert_eq!(hit(100, 0), Some(4));
+        assert_eq!(hit(12, 1), Some(5));
+        assert_eq!(hit(100, 1), Some(6));
+        assert_eq!(hit(100, 2), Some(9));
+        assert_eq!(hit(0, 3), None);
+
+        // Clusters are hit as a whole
+        let to = grid_text("e\u{301}x");
+        let lines = measure(&fontatl, &to);
+        let hit = |x: i32| TextPass::hit_test_lines(&fontatl, &to, &lines, (x, 2 - line_height));
+        assert_eq!([2, 7, 12].map(hit), [Some(0), Some(3), Some(3)]);
+    }
+
+    #[test]
+    fn caret_moves_by_cluster() {
+        let mut to = grid_text("ae\u{301}\u{1f469}\u{200d}\u{1f4bb}");
+        let left: Vec<_> = (0..4).map(|_| {
+            to.caret_left();
+            to.caret
+        }).collect();
+        assert_eq!(left, [Some(4), Some(1), Some(0), Some(0)]);
+        let right: Vec<_> = (0..4).map(|_| {
+            to.caret_right();
+            to.caret
+        }).collect();
+        assert_eq!(right, [Some(1), Some(4), Some(15), Some(15)]);
+    }
+
+    #[test]
+    fn glyphs_load_on_demand() {
+        let mut fontatl = FontAtlas::new().unwrap();
+        fontatl.dirty_rows = None;
+        assert!(!fontatl.glyphs.contains_key(&'\u{3a9}'));
+        fontatl.load_glyphs("\u{3a9}\u{20d7}\u{10ffff}");
+        let omega = fontatl.glyphs[&'\u{3a9}'].clone();
+        assert_ne!(omega.texture_coord, fontatl.fallback.texture_coord);
+        assert!(fontatl.dirty_rows.clone().unwrap().contains(&(omega.texture_coord.1 as u32)));
+        assert!(fontatl.glyphs.contains_key(&'\u{20d7}'));
+        // Characters the font doesn't have use .notdef without taking up space
+        assert_eq!(fontatl.glyphs[&'\u{10ffff}'].texture_coord, fontatl.fallback.texture_coord);
+
+        let mut to = grid_text("\u{3b2}");
+        TextPass::layout_object(&mut fontatl, &mut to, &mut ObjectCache::default());
+        assert!(fontatl.glyphs.contains_key(&'\u{3b2}'));
+    }
 }
@@ -0,0 +1,530 @@
+use crate::ansi::{Perform, Sgr};
+use crate::text::{TextSpan, TextStyle};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cell {
+    c: char,
+    style: TextStyle,
+}
+
+impl Cell {
+    fn blank(style: TextStyle) -> Self {
+        // Erased cells keep the background colour but no other attributes
+        Self { c: ' ', style: TextStyle { background: style.background, ..TextStyle::DEFAULT } }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SavedCursor {
+    row: usize,
+    col: usize,
+    sgr: Sgr,
+}
+
+/// Screen contents of a pane in terminal emulation mode. Implements the VT100/xterm
+/// control sequences needed by full-screen programs; feed it through `ansi::Parser`.
+pub struct Grid {
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<Cell>>,
+    /// The inactive screen, holding the primary screen while the alternate one is shown.
+    other_screen: Option<Vec<Vec<Cell>>>,
+    row: usize,
+    col: usize,
+    wrap_pending: bool,
+    saved: SavedCursor,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    tab_stops: Vec<bool>,
+    sgr: Sgr,
+    autowrap: bool,
+    pub cursor_visible: bool,
+}
+
+impl Grid {
+    const TAB_WIDTH: usize = 8;
+
+    pub fn new(cols: usize, rows: usize) -> Self {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        Self {
+            cols,
+            rows,
+            cells: vec![vec![Cell::blank(TextStyle::DEFAULT); cols]; rows],
+            other_screen: None,
+            row: 0,
+            col: 0,
+            wrap_pending: false,
+            saved: Default::default(),
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            tab_stops: (0..cols).map(|c| c % Self::TAB_WIDTH == 0).collect(),
+            sgr: Default::default(),
+            autowrap: true,
+            cursor_visible: true,
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        // Shrinking keeps the bottom of the screen, where the cursor usually is
+        let dropped = self.rows.saturating_sub(rows);
+        for screen in std::iter::once(&mut self.cells).chain(self.other_screen.as_mut()) {
+            screen.drain(..dropped);
+            screen.resize(rows, vec![Cell::blank(TextStyle::DEFAULT); cols]);
+            for line in screen.iter_mut() {
+                line.resize(cols, Cell::blank(TextStyle::DEFAULT));
+            }
+        }
+        self.row = self.row.saturating_sub(dropped).min(rows - 1);
+        self.col = self.col.min(cols - 1);
+        self.tab_stops.resize(cols, false);
+        for c in self.cols..cols {
+            self.tab_stops[c] = c % Self::TAB_WIDTH == 0;
+        }
+        self.cols = cols;
+        self.rows = rows;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.wrap_pending = false;
+    }
+
+    /// Flattens the screen into rows separated by `\n`, with styled spans and the byte
+    /// offset of the cursor. Trailing unstyled blanks are trimmed, except up to the cursor.
+    pub fn render(&self) -> (String, Vec<TextSpan>, Option<usize>) {
+        let mut text = String::new();
+        let mut spans: Vec<TextSpan> = Vec::new();
+        let mut caret = None;
+        for (r, line) in self.cells.iter().enumerate() {
+            if r > 0 {
+                text.push('\n');
+            }
+            let mut len = line.iter().rposition(|cell| *cell != Cell::blank(TextStyle::DEFAULT)).map_or(0, |p| p + 1);
+            if r == self.row {
+                len = len.max(self.col + 1);
+            }
+            for (c, cell) in line[..len].iter().enumerate() {
+                if r == self.row && c == self.col {
+                    caret = Some(text.len());
+                }
+                let start = text.len();
+                text.push(cell.c);
+                if cell.style == TextStyle::DEFAULT {
+                    continue;
+                }
+                match spans.last_mut() {
+                    Some(last) if last.range.end == start && last.style == cell.style => last.range.end = text.len(),
+                    _ => spans.push(TextSpan { range: start..text.len(), style: cell.style }),
+                }
+            }
+        }
+        (text, spans, caret.filter(|_| self.cursor_visible))
+    }
+
+    fn blank(&self) -> Cell {
+        Cell::blank(self.sgr.style())
+    }
+
+    fn blank_line(&self) -> Vec<Cell> {
+        vec![self.blank(); self.cols]
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        let n = n.min(self.scroll_bottom + 1 - self.scroll_top);
+        let blank = self.blank_line();
+        self.cells[self.scroll_top..=self.scroll_bottom].rotate_left(n);
+        for line in &mut self.cells[self.scroll_bottom + 1 - n..=self.scroll_bottom] {
+            line.clone_from(&blank);
+        }
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        let n = n.min(self.scroll_bottom + 1 - self.scroll_top);
+        let blank = self.blank_line();
+        self.cells[self.scroll_top..=self.scroll_bottom].rotate_right(n);
+        for line in &mut self.cells[self.scroll_top..self.scroll_top + n] {
+            line.clone_from(&blank);
+        }
+    }
+
+    fn index(&mut self) {
+        if self.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.row + 1 < self.rows {
+            self.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.row > 0 {
+            self.row -= 1;
+        }
+    }
+
+    fn goto(&mut self, row: usize, col: usize) {
+        self.row = row.min(self.rows - 1);
+        self.col = col.min(self.cols - 1);
+        self.wrap_pending = false;
+    }
+
+    fn erase(&mut self, row: usize, cols: std::ops::Range<usize>) {
+        let blank = self.blank();
+        let end = cols.end.min(self.cols);
+        for cell in &mut self.cells[row][cols.start.min(end)..end] {
+            *cell = blank;
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = SavedCursor { row: self.row, col: self.col, sgr: self.sgr };
+    }
+
+    fn restore_cursor(&mut self) {
+        let SavedCursor { row, col, sgr } = self.saved;
+        self.sgr = sgr;
+        self.goto(row, col);
+    }
+
+    fn set_alternate_screen(&mut self, enable: bool) {
+        if enable == self.other_screen.is_some() {
+            return;
+        }
+        let other = self.other_screen.take().unwrap_or_else(|| vec![vec![Cell::blank(TextStyle::DEFAULT); self.cols]; self.rows]);
+        let previous = std::mem::replace(&mut self.cells, other);
+        if enable {
+            self.other_screen = Some(previous);
+            for line in &mut self.cells {
+                line.fill(Cell::blank(TextStyle::DEFAULT));
+            }
+        }
+    }
+
+    fn set_mode(&mut self, private: Option<char>, params: &[u16], enable: bool) {
+        if private != Some('?') {
+            return;
+        }
+        for &mode in params {
+            match mode {
+                7 => self.autowrap = enable,
+                25 => self.cursor_visible = enable,
+                47 | 1047 => self.set_alternate_screen(enable),
+                1048 => if enable { self.save_cursor() } else { self.restore_cursor() },
+                1049 => {
+                    if enable {
+                        self.save_cursor();
+                        self.set_alternate_screen(true);
+                    } else {
+                        self.set_alternate_screen(false);
+                        self.restore_cursor();
+                    }
+                }
+                _ => log::debug!("Unhandled private mode {}", mode),
+            }
+        }
+    }
+
+    fn next_tab_stop(&self, col: usize) -> usize {
+        (col + 1..self.cols).find(|&c| self.tab_stops[c]).unwrap_or(self.cols - 1)
+    }
+
+    fn prev_tab_stop(&self, col: usize) -> usize {
+        (0..col).rev().find(|&c| self.tab_stops[c]).unwrap_or(0)
+    }
+}
+
+impl Perform for Grid {
+    fn print(&mut self, c: char) {
+        match c {
+            '\n' => self.index(),
+            '\r' => self.goto(self.row, 0),
+            '\t' => self.goto(self.row, self.next_tab_stop(self.col)),
+            _ => {
+                if self.wrap_pending {
+                    self.goto(self.row, 0);
+                    self.index();
+                }
+                self.cells[self.row][self.col] = Cell { c, style: self.sgr.style() };
+                if self.col + 1 < self.cols {
+                    self.col += 1;
+                } else {
+                    self.wrap_pending = self.autowrap;
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, c: char) {
+        match c {
+            '\x08' => self.goto(self.row, self.col.saturating_sub(1)),
+            '\x0b' | '\x0c' => self.index(),
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &[u16], private: Option<char>, action: char) {
+        let arg = |i: usize, default: u16| match params.get(i) {
+            Some(&0) | None => default as usize,
+            Some(&v) => v as usize,
+        };
+        let n = arg(0, 1);
+        match (private, action) {
+            (None, 'A') => self.goto(self.row.saturating_sub(n).max(if self.row >= self.scroll_top { self.scroll_top } else { 0 }), self.col),
+            (None, 'B') | (None, 'e') => self.goto((self.row + n).min(if self.row <= self.scroll_bottom { self.scroll_bottom } else { self.rows - 1 }), self.col),
+            (None, 'C') | (None, 'a') => self.goto(self.row, self.col + n),
+            (None, 'D') => self.goto(self.row, self.col.saturating_sub(n)),
+            (None, 'E') => self.goto(self.row + n, 0),
+            (None, 'F') => self.goto(self.row.saturating_sub(n), 0),
+            (None, 'G') | (None, '`') => self.goto(self.row, n - 1),
+            (None, 'd') => self.goto(n - 1, self.col),
+            (None, 'H') | (None, 'f') => self.goto(arg(0, 1) - 1, arg(1, 1) - 1),
+            (_, 'J') => {
+                let (row, col) = (self.row, self.col);
+                let rows = match params.first().copied().unwrap_or(0) {
+                    0 => {
+                        self.erase(row, col..self.cols);
+                        row + 1..self.rows
+                    }
+                    1 => {
+                        self.erase(row, 0..col + 1);
+                        0..row
+                    }
+                    _ => 0..self.rows,
+                };
+                for r in rows {
+                    self.erase(r, 0..self.cols);
+                }
+            }
+            (_, 'K') => {
+                let (row, col) = (self.row, self.col);
+                match params.first().copied().unwrap_or(0) {
+                    0 => self.erase(row, col..self.cols),
+                    1 => self.erase(row, 0..col + 1),
+                    _ => self.erase(row, 0..self.cols),
+                }
+            }
+            (None, 'X') => self.erase(self.row, self.col..self.col + n),
+            (None, '@') => {
+                let blank = self.blank();
+                let (row, col, cols) = (self.row, self.col, self.cols);
+                let line = &mut self.cells[row][col..];
+                line.rotate_right(n.min(cols - col));
+                line[..n.min(cols - col)].fill(blank);
+            }
+            (None, 'P') => {
+                let blank = self.blank();
+                let (row, col, cols) = (self.row, self.col, self.cols);
+                let line = &mut self.cells[row][col..];
+                let n = n.min(cols - col);
+                line.rotate_left(n);
+                line[cols - col - n..].fill(blank);
+            }
+            (None, 'L') | (None, 'M') if (self.scroll_top..=self.scroll_bottom).contains(&self.row) => {
+                let top = self.scroll_top;
+                self.scroll_top = self.row;
+                if action == 'L' {
+                    self.scroll_down(n);
+                } else {
+                    self.scroll_up(n);
+                }
+                self.scroll_top = top;
+                self.goto(self.row, 0);
+            }
+            (None, 'S') => self.scroll_up(n),
+            (None, 'T') => self.scroll_down(n),
+            (None, 'r') => {
+                let top = arg(0, 1) - 1;
+                let bottom = arg(1, self.rows as u16).min(self.rows) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.goto(0, 0);
+                }
+            }
+            (None, 'I') => for _ in 0..n {
+                self.goto(self.row, self.next_tab_stop(self.col));
+            },
+            (None, 'Z') => for _ in 0..n {
+                self.goto(self.row, self.prev_tab_stop(self.col));
+            },
+            (None, 'g') => match params.first().copied().unwrap_or(0) {
+                0 => self.tab_stops[self.col] = false,
+                3 => self.tab_stops.fill(false),
+                _ => {}
+            },
+            (None, 'm') => self.sgr.apply(params),
+            (None, 's') => self.save_cursor(),
+            (None, 'u') => self.restore_cursor(),
+            (_, 'h') => self.set_mode(private, params, true),
+            (_, 'l') => self.set_mode(private, params, false),
+            _ => log::debug!("Unhandled CSI {:?} {:?} {}", private, params, action),
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediate: Option<char>, action: char) {
+        if intermediate.is_some() {
+            // Character set designation and similar; nothing to do for UTF-8 input
+            return;
+        }
+        match action {
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            'D' => self.index(),
+            'E' => {
+                self.goto(self.row, 0);
+                self.index();
+            }
+            'M' => self.reverse_index(),
+            'H' => self.tab_stops[self.col] = true,
+            'c' => *self = Grid::new(self.cols, self.rows),
+            _ => log::debug!("Unhandled ESC {}", action),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ansi::Parser;
+
+    fn grid(cols: usize, rows: usize, input: &str) -> Grid {
+        let mut grid = Grid::new(cols, rows);
+        Parser::default().advance(&mut grid, input);
+        grid
+    }
+
+    /// Screen rows without trailing blanks.
+    fn lines(grid: &Grid) -> Vec<String> {
+        grid.render().0.split('\n').map(|line| line.trim_end().to_owned()).collect()
+    }
+
+    fn cursor(grid: &Grid) -> (usize, usize) {
+        (grid.row, grid.col)
+    }
+
+    #[test]
+    fn cursor_movement() {
+        let g = grid(10, 5, "\x1b[3;4Hx");
+        assert_eq!(lines(&g), ["", "", "   x", "", ""]);
+        assert_eq!(cursor(&g), (2, 4));
+        assert_eq!(cursor(&grid(10, 5, "\x1b[3;4H\x1b[2A\x1b[3C")), (0, 6));
+        assert_eq!(cursor(&grid(10, 5, "\x1b[3;4H\x1b[9B\x1b[9D")), (4, 0));
+        assert_eq!(cursor(&grid(10, 5, "\x1b[99;99H")), (4, 9));
+        assert_eq!(cursor(&grid(10, 5, "\x1b[2;2H\x1b[E\x1b[5G")), (2, 4));
+        assert_eq!(cursor(&grid(10, 5, "ab\x1b7\x1b[4;4H\x1b8")), (0, 2));
+        assert_eq!(cursor(&grid(10, 5, "a\tb\x08")), (0, 8));
+    }
+
+    #[test]
+    fn autowrap() {
+        let g = grid(4, 3, "abcdef");
+        assert_eq!(lines(&g), ["abcd", "ef", ""]);
+        let g = grid(4, 3, "\x1b[?7labcdef");
+        assert_eq!(lines(&g), ["abcf", "", ""]);
+        // Writing into the last column doesn't wrap until the next character
+        let g = grid(4, 3, "abcd\r");
+        assert_eq!(cursor(&g), (0, 0));
+    }
+
+    #[test]
+    fn erase() {
+        let full = "abcde\r\nfghij\r\nklmno\x1b[2;3H";
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[K", full))), ["abcde", "fg", "klmno"]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[1K", full))), ["abcde", "   ij", "klmno"]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[2K", full))), ["abcde", "", "klmno"]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[J", full))), ["abcde", "fg", ""]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[1J", full))), ["", "   ij", "klmno"]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[2J", full))), ["", "", ""]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[2X", full))), ["abcde", "fg  j", "klmno"]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[P", full))), ["abcde", "fgij", "klmno"]);
+        assert_eq!(lines(&grid(5, 3, &format!("{}\x1b[@", full))), ["abcde", "fg hi", "klmno"]);
+    }
+
+    #[test]
+    fn erase_keeps_background() {
+        let g = grid(4, 2, "\x1b[44m\x1b[2J");
+        assert!(g.cells.iter().flatten().all(|cell| cell.style.background == Sgr::palette(4) && cell.c == ' '));
+        // One span per row, since the line breaks between them are unstyled
+        let (_, spans, _) = g.render();
+        assert_eq!(spans.len(), 2);
+    }
+
+    #[test]
+    fn scrolling() {
+        let g = grid(3, 3, "a\r\nb\r\nc\r\nd");
+        assert_eq!(lines(&g), ["b", "c", "d"]);
+        let g = grid(3, 3, "a\r\nb\r\nc\x1b[H\x1bM");
+        assert_eq!(lines(&g), ["", "a", "b"]);
+        let g = grid(3, 3, "a\r\nb\r\nc\x1b[S");
+        assert_eq!(lines(&g), ["b", "c", ""]);
+    }
+
+    #[test]
+    fn scroll_region() {
+        let screen = "1\r\n2\r\n3\r\n4\r\n5";
+        // Lines 2 to 4 scroll; the ones outside stay put
+        let g = grid(3, 5, &format!("{}\x1b[2;4r\x1b[4;1H\nx", screen));
+        assert_eq!(lines(&g), ["1", "3", "4", "x", "5"]);
+        let g = grid(3, 5, &format!("{}\x1b[2;4r\x1b[2;1H\x1bM", screen));
+        assert_eq!(lines(&g), ["1", "", "2", "3", "5"]);
+        let g = grid(3, 5, &format!("{}\x1b[2;4r\x1b[3;1H\x1b[L", screen));
+        assert_eq!(lines(&g), ["1", "2", "", "3", "5"]);
+        let g = grid(3, 5, &format!("{}\x1b[2;4r\x1b[2;1H\x1b[2M", screen));
+        assert_eq!(lines(&g), ["1", "4", "", "", "5"]);
+        // Cursor movement stops at the margins
+        assert_eq!(cursor(&grid(3, 5, "\x1b[2;4r\x1b[3;1H\x1b[9B")), (3, 0));
+        assert_eq!(cursor(&grid(3, 5, "\x1b[2;4r\x1b[3;1H\x1b[9A")), (1, 0));
+    }
+
+    #[test]
+    fn alternate_screen() {
+        let mut g = grid(4, 2, "ab\x1b[?1049h");
+        assert_eq!(lines(&g), ["", ""]);
+        Parser::default().advance(&mut g, "\x1b[Hxyz");
+        assert_eq!(lines(&g), ["xyz", ""]);
+        Parser::default().advance(&mut g, "\x1b[?1049l");
+        assert_eq!(lines(&g), ["ab", ""]);
+        assert_eq!(cursor(&g), (0, 2));
+        // Entering again starts from a blank screen
+        Parser::default().advance(&mut g, "\x1b[?1049h");
+        assert_eq!(lines(&g), ["", ""]);
+    }
+
+    #[test]
+    fn resize() {
+        let mut g = grid(4, 3, "a\r\nb\r\ncd");
+        g.resize(6, 2);
+        assert_eq!(g.size(), (6, 2));
+        assert_eq!(lines(&g), ["b", "cd"]);
+        assert_eq!(cursor(&g), (1, 2));
+        g.resize(2, 4);
+        assert_eq!(lines(&g), ["b", "cd", "", ""]);
+        assert_eq!(cursor(&g), (1, 1));
+        // The alternate screen is resized too
+        Parser::default().advance(&mut g, "\x1b[?1049h\x1b[4;2Hz");
+        g.resize(3, 3);
+        assert_eq!(lines(&g), ["", "", " z"]);
+        Parser::default().advance(&mut g, "\x1b[?1049l");
+        assert_eq!(lines(&g), ["cd", "", ""]);
+        assert!(g.cells.iter().all(|line| line.len() == 3));
+        assert_eq!(g.scroll_bottom, 2);
+    }
+
+    #[test]
+    fn render_spans_and_caret() {
+        let g = grid(6, 2, "a\x1b[1mbc\x1b[0md\r\n\x1b[?25l");
+        let (text, spans, caret) = g.render();
+        assert_eq!(text, "abcd\n ");
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].range, 1..3);
+        assert!(spans[0].style.bold);
+        assert_eq!(caret, None);
+        assert_eq!(grid(6, 2, "ab").render().2, Some(2));
+    }
+}